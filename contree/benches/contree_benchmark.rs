@@ -1,7 +1,7 @@
 use contree::Contree;
 use contree::util::morton_code;
 use criterion::{Criterion, criterion_group, criterion_main};
-use glam::Vec3;
+use glam::{IVec3, Vec3};
 use std::hint::black_box;
 
 fn create_contree(size: u32, p: Vec3) -> Contree<'static> {
@@ -10,7 +10,7 @@ fn create_contree(size: u32, p: Vec3) -> Contree<'static> {
         size,
         ..Default::default()
     };
-    contree.insert(p, 10);
+    contree.insert(p, 10).unwrap();
     contree
 }
 
@@ -36,7 +36,7 @@ fn raycast_benchmark(c: &mut Criterion) {
 
         let mut dir = Vec3::new(-distance, 1., 0.);
         while dir.y > -1. {
-            contree.raycast(black_box(pos), black_box(dir));
+            let _ = contree.raycast(black_box(pos), black_box(dir));
 
             // round to account for FPE
             dir = ((dir + Vec3::new(0., -0.0005, 0.)) / 0.0005).round() * 0.0005;
@@ -47,6 +47,47 @@ fn raycast_benchmark(c: &mut Criterion) {
         b.iter(|| contree.raycast(Vec3::splat(50.), Vec3::splat(-1.)))
     });
     c.bench_function("fan", |b| b.iter(|| fan(black_box(&contree))));
+
+    // the same fan, cast as one batch
+    let pos = Vec3::new(5.5, 0., 0.);
+    let rays: Vec<_> = (-2000..=2000)
+        .map(|i| (pos, Vec3::new(-5., i as f32 * 0.0005, 0.)))
+        .collect();
+    c.bench_function("fan sequential", |b| {
+        b.iter(|| {
+            black_box(&rays)
+                .iter()
+                .map(|&(pos, dir)| contree.raycast(pos, dir))
+                .collect::<Vec<_>>()
+        })
+    });
+    c.bench_function("fan many", |b| {
+        b.iter(|| contree.raycast_many(black_box(&rays)))
+    });
+
+    // rays from a single eye down onto a floor, deep enough that finding the eye is costly
+    let mut contree = create_contree(4u32.pow(6), Vec3::splat(5.));
+    contree
+        .fill_aabb(IVec3::new(-200, -10, -200), IVec3::new(200, -8, 200), 1)
+        .unwrap();
+    let eye = Vec3::new(0.5, 20.25, 0.5);
+    let rays: Vec<_> = (0..64 * 64)
+        .map(|i| {
+            let (x, z) = ((i % 64) as f32 - 32., (i / 64) as f32 - 32.);
+            (eye, Vec3::new(x, -40., z))
+        })
+        .collect();
+    c.bench_function("eye sequential", |b| {
+        b.iter(|| {
+            black_box(&rays)
+                .iter()
+                .map(|&(pos, dir)| contree.raycast(pos, dir))
+                .collect::<Vec<_>>()
+        })
+    });
+    c.bench_function("eye many", |b| {
+        b.iter(|| contree.raycast_many(black_box(&rays)))
+    });
 }
 
 fn util_benchmark(c: &mut Criterion) {
@@ -0,0 +1,164 @@
+use std::{
+    borrow::Cow,
+    ops::{Index, IndexMut, Range},
+    sync::Arc,
+};
+
+/// Nodes held by each chunk of an [`Arena`]
+pub const CHUNK_LEN: usize = 256;
+
+/// Growable array of nodes split into reference counted chunks
+///
+/// Cloning only copies the chunk pointers. A chunk shared with a clone is copied the first
+/// time it is written to, so clones behave like independent vectors while sharing every
+/// chunk neither side has changed.
+#[derive(Debug, Clone)]
+pub struct Arena<T> {
+    chunks: Vec<Arc<Vec<T>>>,
+    len: usize,
+}
+
+impl<T> Default for Arena<T> {
+    fn default() -> Self {
+        Self {
+            chunks: Vec::new(),
+            len: 0,
+        }
+    }
+}
+
+impl<T: Clone> Arena<T> {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, index: usize) -> Option<&T> {
+        (index < self.len).then(|| &self.chunks[index / CHUNK_LEN][index % CHUNK_LEN])
+    }
+
+    pub fn push(&mut self, value: T) {
+        if self.len.is_multiple_of(CHUNK_LEN) {
+            self.chunks.push(Arc::new(Vec::with_capacity(CHUNK_LEN)));
+        }
+        Arc::make_mut(self.chunks.last_mut().unwrap()).push(value);
+        self.len += 1;
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.chunks.iter().flat_map(|chunk| chunk.iter())
+    }
+
+    /// A range of nodes as one slice, borrowed from its chunk or copied if it spans several
+    ///
+    /// The range is cut off at the end of the arena.
+    pub fn contiguous(&self, range: Range<usize>) -> Cow<'_, [T]> {
+        let (start, end) = (range.start.min(self.len), range.end.min(self.len));
+        if start == end {
+            return Cow::Borrowed(&[]);
+        }
+        let offset = start % CHUNK_LEN;
+        if end - start + offset <= CHUNK_LEN {
+            return Cow::Borrowed(&self.chunks[start / CHUNK_LEN][offset..end - start + offset]);
+        }
+        let chunks = &self.chunks[start / CHUNK_LEN..end.div_ceil(CHUNK_LEN)];
+        let values = chunks.iter().flat_map(|chunk| chunk.iter());
+        Cow::Owned(values.skip(offset).take(end - start).cloned().collect())
+    }
+
+    /// Chunks not shared with any clone
+    pub fn unshared_chunks(&self) -> usize {
+        self.chunks
+            .iter()
+            .filter(|chunk| Arc::strong_count(chunk) == 1)
+            .count()
+    }
+}
+
+impl<T: Clone> Index<usize> for Arena<T> {
+    type Output = T;
+
+    fn index(&self, index: usize) -> &T {
+        match self.get(index) {
+            Some(value) => value,
+            None => panic!("index {index} out of bounds for arena of {}", self.len),
+        }
+    }
+}
+
+impl<T: Clone> IndexMut<usize> for Arena<T> {
+    /// Copies the chunk holding the node first if it is shared
+    fn index_mut(&mut self, index: usize) -> &mut T {
+        assert!(
+            index < self.len,
+            "index {index} out of bounds for arena of {}",
+            self.len
+        );
+        &mut Arc::make_mut(&mut self.chunks[index / CHUNK_LEN])[index % CHUNK_LEN]
+    }
+}
+
+impl<T: Clone> FromIterator<T> for Arena<T> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        let mut arena = Self::default();
+        for value in iter {
+            arena.push(value);
+        }
+        arena
+    }
+}
+
+impl<T: Clone> From<Vec<T>> for Arena<T> {
+    fn from(values: Vec<T>) -> Self {
+        values.into_iter().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clones_share_chunks() {
+        let mut arena: Arena<u32> = (0..CHUNK_LEN as u32 * 3).collect();
+        let clone = arena.clone();
+        assert_eq!(arena.unshared_chunks(), 0);
+
+        arena[CHUNK_LEN + 5] = 1000;
+        arena.push(7);
+        assert_eq!(arena.unshared_chunks(), 2);
+        assert_eq!(clone.unshared_chunks(), 1);
+
+        assert_eq!(arena[CHUNK_LEN + 5], 1000);
+        assert_eq!(clone[CHUNK_LEN + 5], CHUNK_LEN as u32 + 5);
+        assert_eq!(arena.len(), clone.len() + 1);
+        assert_eq!(clone.get(clone.len()), None);
+    }
+
+    #[test]
+    fn contiguous_spans_chunks() {
+        let arena: Arena<u32> = (0..CHUNK_LEN as u32 * 2 + 10).collect();
+
+        let within = arena.contiguous(CHUNK_LEN + 3..CHUNK_LEN + 9);
+        assert!(matches!(within, Cow::Borrowed(_)));
+        assert!(
+            within
+                .iter()
+                .copied()
+                .eq(CHUNK_LEN as u32 + 3..CHUNK_LEN as u32 + 9)
+        );
+
+        let across = arena.contiguous(CHUNK_LEN - 2..arena.len() + 5);
+        assert!(
+            across
+                .iter()
+                .copied()
+                .eq(CHUNK_LEN as u32 - 2..arena.len() as u32)
+        );
+        assert!(arena.contiguous(0..arena.len()).iter().eq(arena.iter()));
+        assert!(arena.contiguous(arena.len()..arena.len() + 1).is_empty());
+    }
+}
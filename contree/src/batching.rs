@@ -0,0 +1,268 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::{Mutex, MutexGuard, PoisonError},
+};
+
+use glam::DVec3;
+
+use super::{Addr, ContreeInner, ContreeLeaf, GPUBindable, Material};
+
+/// Writes waiting for the next flush, only the latest data for each address is kept
+#[derive(Debug, Default)]
+struct Pending {
+    resync: bool,
+    arenas: Option<(usize, usize)>,
+    inners: BTreeMap<Addr, ContreeInner>,
+    leaves: BTreeMap<Addr, ContreeLeaf>,
+    /// Freed nodes that were not allocated again since
+    freed_inners: BTreeSet<Addr>,
+    freed_leaves: BTreeSet<Addr>,
+    materials: Option<Vec<Material>>,
+    bounds: Option<(Option<Addr>, u32, DVec3)>,
+}
+
+/// Binding that holds writes back until [`BatchingBinding::flush`], then forwards them
+///
+/// Nodes written several times between flushes are only forwarded once, and every run of
+/// consecutive dirty addresses becomes a single write, so a frame of edits costs a write per
+/// run instead of one per change.
+/// Notifications are forwarded in an order the target can apply directly: a resync first,
+/// then the arena lengths, the node writes, the frees, the palette and finally the bounds.
+#[derive(Debug)]
+pub struct BatchingBinding<B> {
+    pub target: B,
+    pending: Mutex<Pending>,
+}
+
+impl<B: GPUBindable> BatchingBinding<B> {
+    pub fn new(target: B) -> Self {
+        Self {
+            target,
+            pending: Mutex::default(),
+        }
+    }
+
+    fn pending(&self) -> MutexGuard<'_, Pending> {
+        // a panicking writer leaves every recorded write intact
+        self.pending.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Whether any writes are waiting for a flush
+    pub fn is_dirty(&self) -> bool {
+        let pending = self.pending();
+        pending.resync
+            || pending.arenas.is_some()
+            || !pending.inners.is_empty()
+            || !pending.leaves.is_empty()
+            || !pending.freed_inners.is_empty()
+            || !pending.freed_leaves.is_empty()
+            || pending.materials.is_some()
+            || pending.bounds.is_some()
+    }
+
+    /// Forward everything pending to the target, returning how many writes it took
+    pub fn flush(&self) -> usize {
+        let Pending {
+            resync,
+            arenas,
+            inners,
+            leaves,
+            freed_inners,
+            freed_leaves,
+            materials,
+            bounds,
+        } = std::mem::take(&mut *self.pending());
+
+        if resync {
+            self.target.resync();
+        }
+        if let Some((inners, leaves)) = arenas {
+            self.target.arenas_grown(inners, leaves);
+        }
+        let mut writes = 0;
+        for (start, data) in runs(inners) {
+            self.target.write_inner(start, &data);
+            writes += 1;
+        }
+        for (start, data) in runs(leaves) {
+            self.target.write_leaf(start, &data);
+            writes += 1;
+        }
+        for addr in freed_inners {
+            self.target.free_inner(addr);
+        }
+        for addr in freed_leaves {
+            self.target.free_leaf(addr);
+        }
+        if let Some(materials) = materials {
+            self.target.write_materials(&materials);
+            writes += 1;
+        }
+        if let Some((root, size, center_offset)) = bounds {
+            self.target.bounds_changed(root, size, center_offset);
+        }
+        writes
+    }
+}
+
+impl<B: GPUBindable> GPUBindable for BatchingBinding<B> {
+    fn write_inner(&self, addr: Addr, data: &[ContreeInner]) {
+        let mut pending = self.pending();
+        for (addr, node) in (addr..).zip(data) {
+            pending.freed_inners.remove(&addr);
+            pending.inners.insert(addr, *node);
+        }
+    }
+
+    fn write_leaf(&self, addr: Addr, data: &[ContreeLeaf]) {
+        let mut pending = self.pending();
+        for (addr, node) in (addr..).zip(data) {
+            pending.freed_leaves.remove(&addr);
+            pending.leaves.insert(addr, *node);
+        }
+    }
+
+    fn write_materials(&self, data: &[Material]) {
+        self.pending().materials = Some(data.to_vec());
+    }
+
+    fn bounds_changed(&self, root: Option<Addr>, size: u32, center_offset: DVec3) {
+        self.pending().bounds = Some((root, size, center_offset));
+    }
+
+    fn arenas_grown(&self, inners: usize, leaves: usize) {
+        self.pending().arenas = Some((inners, leaves));
+    }
+
+    fn free_inner(&self, addr: Addr) {
+        self.pending().freed_inners.insert(addr);
+    }
+
+    fn free_leaf(&self, addr: Addr) {
+        self.pending().freed_leaves.insert(addr);
+    }
+
+    fn resync(&self) {
+        *self.pending() = Pending {
+            resync: true,
+            ..Default::default()
+        };
+    }
+}
+
+/// Split nodes sorted by address into runs of consecutive addresses
+fn runs<T>(nodes: BTreeMap<Addr, T>) -> impl Iterator<Item = (Addr, Vec<T>)> {
+    let mut nodes = nodes.into_iter().peekable();
+    std::iter::from_fn(move || {
+        let (start, first) = nodes.next()?;
+        let mut data = vec![first];
+        while let Some((_, node)) = nodes.next_if(|&(addr, _)| addr == start + data.len() as Addr) {
+            data.push(node);
+        }
+        Some((start, data))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::{IVec3, Vec3};
+
+    use super::*;
+    use crate::Contree;
+    use crate::test_util::WriteLog;
+
+    #[test]
+    fn flush_merges_runs() {
+        let binding = BatchingBinding::new(WriteLog::default());
+        let leaf = |contains| ContreeLeaf {
+            contains,
+            light: 0,
+            children: [0; 64],
+        };
+        binding.write_leaf(3, &[leaf(1)]);
+        binding.write_leaf(1, &[leaf(2), leaf(3)]);
+        binding.write_leaf(3, &[leaf(4)]);
+        binding.write_leaf(7, &[leaf(5)]);
+        assert!(binding.target.leaves.lock().unwrap().is_empty());
+        assert!(binding.is_dirty());
+
+        assert_eq!(binding.flush(), 2);
+        assert!(!binding.is_dirty());
+        let written: Vec<_> = binding
+            .target
+            .leaves
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(addr, data)| (*addr, data.iter().map(|l| l.contains).collect::<Vec<_>>()))
+            .collect();
+        assert_eq!(written, &[(1, vec![2, 3, 4]), (7, vec![5])]);
+
+        assert_eq!(binding.flush(), 0);
+    }
+
+    #[test]
+    fn resync_drops_pending_writes() {
+        let binding = BatchingBinding::new(WriteLog::default());
+        let mut contree = Contree::new(&binding).unwrap();
+        contree.insert(Vec3::ZERO, 10).unwrap();
+        contree.remove(Vec3::ZERO).unwrap();
+        {
+            let pending = binding.pending();
+            assert_eq!(pending.freed_leaves.len(), 1);
+            assert_eq!(pending.bounds, Some((Some(0), 16, DVec3::ZERO)));
+        }
+
+        // the freed nodes are reused, so they are live again
+        contree.insert(Vec3::ONE, 4).unwrap();
+        assert!(binding.pending().freed_leaves.is_empty());
+        assert!(binding.pending().freed_inners.is_empty());
+
+        contree.compact();
+        {
+            let pending = binding.pending();
+            assert!(pending.resync);
+            assert_eq!(pending.arenas, Some((1, 1)));
+            assert_eq!(pending.inners.len(), 1);
+        }
+        assert_eq!(binding.flush(), 3);
+        assert!(!binding.is_dirty());
+    }
+
+    #[test]
+    fn batched_edits_match_tree() {
+        let binding = BatchingBinding::new(WriteLog::default());
+        let mut contree = Contree::new(&binding).unwrap();
+        contree.insert(Vec3::ZERO, 10).unwrap();
+        for i in 0..50 {
+            contree.insert(Vec3::new(i as f32, 3., -2.), 2).unwrap();
+        }
+        contree
+            .fill_aabb(IVec3::new(-5, -5, -5), IVec3::new(5, -1, 5), 4)
+            .unwrap();
+        contree.remove(Vec3::new(10., 3., -2.)).unwrap();
+
+        let writes = binding.flush();
+        assert!(writes < 10, "{writes} writes");
+        assert_eq!(*binding.target.materials.lock().unwrap(), 1);
+
+        // every node was written once with its latest data
+        let inners = binding.target.inners.lock().unwrap();
+        let written: Vec<_> = inners
+            .iter()
+            .flat_map(|(addr, data)| (*addr..).zip(data))
+            .collect();
+        assert_eq!(written.len(), contree.inners.len());
+        for (addr, node) in written {
+            let live = contree.inners[addr as usize];
+            assert_eq!(bytemuck::bytes_of(node), bytemuck::bytes_of(&live));
+        }
+        let leaves = binding.target.leaves.lock().unwrap();
+        for (addr, data) in leaves.iter() {
+            for (addr, leaf) in (*addr..).zip(data) {
+                let live = contree.leaves[addr as usize];
+                assert_eq!(bytemuck::bytes_of(leaf), bytemuck::bytes_of(&live));
+            }
+        }
+    }
+}
@@ -0,0 +1,434 @@
+use std::collections::BTreeSet;
+
+use glam::Vec3;
+use rayon::prelude::*;
+
+use super::{
+    AIR, Addr, Contree, ContreeError, ContreeInner, ContreeLeaf, lod::most_common,
+    node_management::Child, palette::Palette, util::*,
+};
+
+/// Nodes built outside of the tree, with addresses local to its own arenas
+#[derive(Debug, Default)]
+struct Subtree {
+    inners: Vec<ContreeInner>,
+    leaves: Vec<ContreeLeaf>,
+}
+
+impl Subtree {
+    /// Build the node holding a run of points sorted by morton code
+    ///
+    /// `index` is the morton index of the node's children, later duplicates overwrite earlier ones.
+    /// Nodes filled with one material are returned as solid children instead.
+    fn build(&mut self, points: &[(MortonCode, u8)], index: u8, palette: &Palette) -> Child {
+        if index == MAX_MORTON_INDEX {
+            let mut leaf = ContreeLeaf {
+                contains: 0,
+                light: 0,
+                children: [0; 64],
+            };
+            for &(code, material) in points {
+                let i = morton_index(code, index).unwrap();
+                leaf.contains |= 1 << i;
+                leaf.light =
+                    (leaf.light & !(1 << i)) | ((palette.is_emissive(material) as u64) << i);
+                leaf.children[i as usize] = material;
+            }
+            if let Some(material) = leaf.uniform() {
+                return Child::Solid(material);
+            }
+            self.leaves.push(leaf);
+            return Child::Leaf((self.leaves.len() - 1) as Addr);
+        }
+
+        let addr = self.inners.len();
+        self.inners.push(ContreeInner {
+            contains: 0,
+            leaf: 0,
+            solid: 0,
+            light: 0,
+            material: 0,
+            padding: 0,
+            children: [0; 64],
+        });
+        for group in points.chunk_by(|a, b| morton_index(a.0, index) == morton_index(b.0, index)) {
+            let i = morton_index(group[0].0, index).unwrap();
+            let child = self.build(group, index + 1, palette);
+            self.inners[addr].set_child(i, child);
+            let lit = self.is_lit(child, palette);
+            self.inners[addr].set_light(i, lit);
+        }
+        if let Some(material) = self.inners[addr].uniform() {
+            // every node after this one is below it
+            self.inners.truncate(addr);
+            return Child::Solid(material);
+        }
+        let node = self.inners[addr];
+        self.inners[addr].material = most_common(
+            (0..64)
+                .filter_map(|i| self.representative(node.child(i)))
+                .filter(|&m| m != AIR),
+        ) as u32;
+        Child::Inner(addr as Addr)
+    }
+
+    fn representative(&self, child: Child) -> Option<u8> {
+        match child {
+            Child::Empty => None,
+            Child::Solid(material) => Some(material),
+            Child::Inner(addr) => Some(self.inners[addr as usize].material as u8),
+            Child::Leaf(addr) => Some(self.leaves[addr as usize].representative()),
+        }
+    }
+
+    fn is_lit(&self, child: Child, palette: &Palette) -> bool {
+        match child {
+            Child::Empty => false,
+            Child::Inner(addr) => self.inners[addr as usize].light != 0,
+            Child::Leaf(addr) => self.leaves[addr as usize].light != 0,
+            Child::Solid(material) => palette.is_emissive(material),
+        }
+    }
+}
+
+/// Nodes changed during a bulk operation, uploaded together at the end
+#[derive(Debug, Default)]
+struct Dirty {
+    inners: BTreeSet<Addr>,
+    leaves: BTreeSet<Addr>,
+}
+
+impl Contree<'_> {
+    /// Insert many voxels at once
+    ///
+    /// Points are sorted by morton code and every child of the root is built in parallel,
+    /// changes are uploaded as contiguous ranges once the tree is complete.
+    /// When a position appears more than once the last material wins.
+    /// Fails without changing any voxel if a position is not finite or past the largest tree.
+    pub fn insert_many(
+        &mut self,
+        points: impl IntoIterator<Item = (Vec3, u8)>,
+    ) -> Result<(), ContreeError> {
+        let points: Vec<(Vec3, u8)> = points.into_iter().collect();
+        // min and max skip NaN, so the box alone would not catch it
+        if let Some(&(p, _)) = points.iter().find(|&&(p, _)| !is_addressable(p)) {
+            return Err(ContreeError::OutOfRange(p));
+        }
+        let Some((min, max)) = points
+            .iter()
+            .map(|&(p, _)| (p, p))
+            .reduce(|(min, max), (p, _)| (min.min(p), max.max(p)))
+        else {
+            return Ok(());
+        };
+        self.grow_to_cover(min, max)?;
+        let root = self.checked_root()?;
+        self.check_links(root)?;
+        let mut codes: Vec<(MortonCode, u8)> = points
+            .par_iter()
+            .map(|&(p, material)| Ok((self.locate(p)?, material)))
+            .collect::<Result<_, ContreeError>>()?;
+        for &(p, material) in &points {
+            self.record(p, material)?;
+        }
+
+        // stable so that duplicates keep their input order
+        codes.par_sort_by_key(|&(code, _)| code);
+
+        let first = self.first_morton_index();
+        let palette = &self.palette;
+        let subtrees: Vec<_> = codes
+            .chunk_by(|a, b| morton_index(a.0, first) == morton_index(b.0, first))
+            .collect::<Vec<_>>()
+            .into_par_iter()
+            .map(|group| {
+                let mut subtree = Subtree::default();
+                let child = subtree.build(group, first + 1, palette);
+                (morton_index(group[0].0, first).unwrap(), subtree, child)
+            })
+            .collect();
+
+        let mut dirty = Dirty::default();
+        dirty.inners.insert(root);
+        for (index, subtree, child) in subtrees {
+            match self.inners[root as usize].child(index) {
+                Child::Empty => {
+                    let grafted = self.graft(&subtree, child, &mut dirty)?;
+                    self.inners[root as usize].set_child(index, grafted);
+                }
+                existing => {
+                    let merged = self.merge(existing, &subtree, child, &mut dirty)?;
+                    self.inners[root as usize].set_child(index, merged);
+                }
+            }
+            let lit = self.is_lit(self.inners[root as usize].child(index));
+            self.inners[root as usize].set_light(index, lit);
+        }
+        self.update_material(root);
+        self.write_ranges(&dirty.inners, &dirty.leaves);
+        self.check_invariants();
+        Ok(())
+    }
+
+    /// Copy a node of a subtree and everything below it into the tree
+    fn graft(
+        &mut self,
+        subtree: &Subtree,
+        child: Child,
+        dirty: &mut Dirty,
+    ) -> Result<Child, ContreeError> {
+        Ok(match child {
+            Child::Empty => Child::Empty,
+            Child::Solid(material) => Child::Solid(material),
+            Child::Leaf(local) => {
+                let addr = self.alloc_leaf_node()?;
+                self.leaves[addr as usize] = subtree.leaves[local as usize];
+                dirty.leaves.insert(addr);
+                Child::Leaf(addr)
+            }
+            Child::Inner(local) => {
+                let addr = self.alloc_inner_node()?;
+                let node = subtree.inners[local as usize];
+                for i in 0..64 {
+                    let grafted = self.graft(subtree, node.child(i), dirty)?;
+                    self.inners[addr as usize].set_child(i, grafted);
+                }
+                self.inners[addr as usize].light = node.light;
+                self.inners[addr as usize].material = node.material;
+                dirty.inners.insert(addr);
+                Child::Inner(addr)
+            }
+        })
+    }
+
+    /// Merge a node of a subtree into an existing node at the same position
+    ///
+    /// Returns what the existing child became, since solid children are expanded and collapsed.
+    /// Every node is checked before it is changed, a corrupted tree may be left partially merged.
+    fn merge(
+        &mut self,
+        existing: Child,
+        subtree: &Subtree,
+        child: Child,
+        dirty: &mut Dirty,
+    ) -> Result<Child, ContreeError> {
+        match (existing, child) {
+            (_, Child::Solid(_)) => {
+                self.check_subtree(existing)?;
+                self.free_child(existing);
+                Ok(child)
+            }
+            (Child::Solid(material), _) => {
+                let expanded = self.expand_solid(material, matches!(child, Child::Leaf(_)))?;
+                self.merge(expanded, subtree, child, dirty)
+            }
+            (Child::Leaf(addr), Child::Leaf(local)) => {
+                let new = subtree.leaves[local as usize];
+                let leaf = &mut self.leaves[addr as usize];
+                leaf.contains |= new.contains;
+                leaf.light = (leaf.light & !new.contains) | new.light;
+                for i in 0..64 {
+                    if (new.contains >> i) & 1 == 1 {
+                        leaf.children[i] = new.children[i];
+                    }
+                }
+                dirty.leaves.insert(addr);
+                Ok(self.simplify(existing))
+            }
+            (Child::Inner(addr), Child::Inner(local)) => {
+                self.check_links(addr)?;
+                let node = subtree.inners[local as usize];
+                for i in 0..64 {
+                    match (self.inners[addr as usize].child(i), node.child(i)) {
+                        (_, Child::Empty) => {}
+                        (Child::Empty, new) => {
+                            let grafted = self.graft(subtree, new, dirty)?;
+                            self.inners[addr as usize].set_child(i, grafted);
+                        }
+                        (existing, new) => {
+                            let merged = self.merge(existing, subtree, new, dirty)?;
+                            self.inners[addr as usize].set_child(i, merged);
+                        }
+                    }
+                    let lit = self.is_lit(self.inners[addr as usize].child(i));
+                    self.inners[addr as usize].set_light(i, lit);
+                }
+                self.update_material(addr);
+                dirty.inners.insert(addr);
+                Ok(self.simplify(existing))
+            }
+            _ => unreachable!("Nodes at the same depth have the same kind!"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arena::CHUNK_LEN;
+    use crate::test_util::{WriteLog, corrupt_root_link, create_contree};
+
+    /// Deterministic scattered points in a cube of the given radius
+    fn scattered_points(count: usize, radius: i32) -> Vec<(Vec3, u8)> {
+        let mut state: u64 = 0x2545f4914f6cdd1d;
+        let mut next = move || {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (state >> 32) as i32
+        };
+        (0..count)
+            .map(|_| {
+                let p = Vec3::new(
+                    (next() % radius) as f32,
+                    (next() % radius) as f32,
+                    (next() % radius) as f32,
+                );
+                (p, (next() % 255 + 1) as u8)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn insert_many_matches_insert() {
+        let points = scattered_points(2000, 120);
+
+        let mut bulk = create_contree(256, Vec3::splat(-127.));
+        bulk.insert_many(points.iter().copied()).unwrap();
+
+        let mut sequential = create_contree(256, Vec3::splat(-127.));
+        for &(p, material) in &points {
+            sequential.insert(p, material).unwrap();
+        }
+
+        for &(p, _) in &points {
+            assert_eq!(
+                bulk.find(p).unwrap().material,
+                sequential.find(p).unwrap().material
+            );
+        }
+        assert_eq!(bulk.find(Vec3::splat(-127.)).unwrap().material, Some(10));
+        assert_eq!(bulk.inners.len(), sequential.inners.len());
+        assert_eq!(bulk.leaves.len(), sequential.leaves.len());
+    }
+
+    #[test]
+    fn insert_many_last_duplicate_wins() {
+        let mut contree = create_contree(64, Vec3::ZERO);
+        contree
+            .insert_many([
+                (Vec3::ONE, 1),
+                (Vec3::ZERO, 2),
+                (Vec3::ONE, 3),
+                (Vec3::ONE, 4),
+            ])
+            .unwrap();
+
+        assert_eq!(contree.find(Vec3::ONE).unwrap().material, Some(4));
+        assert_eq!(contree.find(Vec3::ZERO).unwrap().material, Some(2));
+    }
+
+    #[test]
+    fn insert_many_empty() {
+        let mut contree = create_contree(64, Vec3::ZERO);
+        contree.insert_many([]).unwrap();
+
+        assert_eq!(contree.size, 64);
+        assert_eq!(contree.leaves.len(), 1);
+    }
+
+    #[test]
+    fn insert_many_coalesces_writes() {
+        let log = WriteLog::default();
+        let mut contree = Contree::new(&log).unwrap();
+        contree.size = 256;
+        log.clear();
+
+        contree.insert_many(scattered_points(500, 100)).unwrap();
+
+        // every node is new, so each arena is a single run even across chunks
+        assert!(contree.leaves.len() > CHUNK_LEN);
+        assert_eq!(log.inner_runs(), &[(0, contree.inners.len())]);
+        assert_eq!(log.leaf_runs(), &[(0, contree.leaves.len())]);
+    }
+
+    #[test]
+    fn insert_many_collapses_uniform_nodes() {
+        let mut contree = create_contree(64, Vec3::splat(-20.));
+        contree
+            .fill_aabb(
+                glam::IVec3::new(-16, -16, -16),
+                glam::IVec3::new(-1, -1, -1),
+                2,
+            )
+            .unwrap();
+        // a 16 block of one material next to the solid one, and a block overwriting it
+        let points = (0..16 * 16 * 16).map(|i| {
+            let p = Vec3::new((i % 16) as f32, ((i / 16) % 16) as f32, (i / 256) as f32);
+            (p, 4)
+        });
+        contree
+            .insert_many(points.chain([(Vec3::splat(-16.), 5)]))
+            .unwrap();
+
+        let root = contree.inners[contree.root.unwrap() as usize];
+        assert_eq!(root.solid.count_ones(), 1);
+        assert_eq!(contree.find(Vec3::splat(8.)).unwrap().material, Some(4));
+        assert_eq!(contree.find(Vec3::splat(-16.)).unwrap().material, Some(5));
+        assert_eq!(contree.find(Vec3::splat(-15.)).unwrap().material, Some(2));
+    }
+
+    #[test]
+    fn insert_many_into_fresh_tree() {
+        let mut contree = Contree::default();
+        contree
+            .insert_many([(Vec3::ZERO, 1), (Vec3::splat(20.), 2)])
+            .unwrap();
+
+        assert_eq!(contree.find(Vec3::ZERO).unwrap().material, Some(1));
+        assert_eq!(contree.find(Vec3::splat(20.)).unwrap().material, Some(2));
+        assert_eq!(contree.iter().count(), 2);
+    }
+
+    #[test]
+    fn insert_many_out_of_range() {
+        let mut contree = create_contree(16, Vec3::ZERO);
+        contree.max_depth = 2;
+        let far = Vec3::splat(100.);
+
+        assert_eq!(
+            contree.insert_many([(Vec3::ONE, 2), (far, 3)]),
+            Err(ContreeError::OutOfRange(far))
+        );
+        assert_eq!(
+            contree.iter().collect::<Vec<_>>(),
+            &[(glam::IVec3::ZERO, 10)]
+        );
+    }
+
+    #[test]
+    fn insert_many_rejects_nan() {
+        let mut contree = create_contree(16, Vec3::ZERO);
+
+        assert!(matches!(
+            contree.insert_many([(Vec3::ONE, 2), (Vec3::NAN, 3)]),
+            Err(ContreeError::OutOfRange(_))
+        ));
+        assert!(matches!(
+            contree.insert_many([(Vec3::NAN, 3)]),
+            Err(ContreeError::OutOfRange(_))
+        ));
+        assert_eq!(contree.iter().count(), 1);
+    }
+
+    #[test]
+    fn insert_many_reports_corrupted_tree() {
+        let mut contree = create_contree(64, Vec3::ZERO);
+        let corrupted = corrupt_root_link(&mut contree);
+
+        assert_eq!(
+            contree.insert_many([(Vec3::ONE, 3), (Vec3::splat(20.), 4)]),
+            Err(corrupted)
+        );
+    }
+}
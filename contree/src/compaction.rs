@@ -0,0 +1,144 @@
+use bytemuck::Zeroable;
+
+use super::{Addr, Contree, ContreeInner, ContreeLeaf, node_management::Child};
+
+/// Copy of the live nodes of a tree laid out in depth-first order, the root is always at 0
+#[derive(Debug, Default)]
+pub(crate) struct CompactArenas {
+    pub inners: Vec<ContreeInner>,
+    pub leaves: Vec<ContreeLeaf>,
+}
+
+impl CompactArenas {
+    fn copy(&mut self, contree: &Contree, addr: Addr) -> Addr {
+        let new_addr = self.inners.len();
+        self.inners.push(ContreeInner::zeroed());
+
+        let mut node = contree.inners[addr as usize];
+        for i in 0..64 {
+            let child = match node.child(i) {
+                Child::Empty => continue,
+                Child::Solid(material) => Child::Solid(material),
+                Child::Inner(child) => Child::Inner(self.copy(contree, child)),
+                Child::Leaf(child) => {
+                    self.leaves.push(contree.leaves[child as usize]);
+                    Child::Leaf((self.leaves.len() - 1) as Addr)
+                }
+            };
+            node.set_child(i, child);
+        }
+
+        self.inners[new_addr] = node;
+        new_addr as Addr
+    }
+}
+
+impl Contree<'_> {
+    /// Live nodes reachable from the root, in depth-first order
+    ///
+    /// Since children are visited in index order, leaves end up sorted by morton code
+    pub(crate) fn compacted(&self) -> CompactArenas {
+        let mut arenas = CompactArenas::default();
+        if let Some(root) = self.root {
+            arenas.copy(self, root);
+        }
+        arenas
+    }
+
+    /// Rewrite both arenas in depth-first order, dropping every tombstone
+    ///
+    /// Everything is uploaded again afterward since every address may have changed
+    pub fn compact(&mut self) {
+        let CompactArenas { inners, leaves } = self.compacted();
+        if self.root.is_some() {
+            self.root = Some(0);
+        }
+        self.inners = inners.into();
+        self.leaves = leaves.into();
+        self.inner_tombstones.clear();
+        self.leaf_tombstones.clear();
+
+        self.write_all();
+        self.check_invariants();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::{IVec3, Vec3};
+
+    use super::*;
+    use crate::test_util::{WriteLog, create_contree};
+
+    #[test]
+    fn compact_drops_tombstones() {
+        let mut contree = create_contree(256, Vec3::ZERO);
+        for i in 1..20 {
+            contree.insert(Vec3::splat(i as f32 * 6.), i).unwrap();
+            contree.insert(Vec3::splat(i as f32 * -6.), i).unwrap();
+        }
+        for i in (1..20).step_by(2) {
+            contree.remove(Vec3::splat(i as f32 * 6.)).unwrap();
+        }
+        let live_inners = contree.inners.len() - contree.inner_tombstones.len();
+        let live_leaves = contree.leaves.len() - contree.leaf_tombstones.len();
+        assert!(!contree.leaf_tombstones.is_empty());
+
+        contree.compact();
+
+        assert_eq!(contree.root, Some(0));
+        assert!(contree.inner_tombstones.is_empty());
+        assert!(contree.leaf_tombstones.is_empty());
+        assert_eq!(contree.inners.len(), live_inners);
+        assert_eq!(contree.leaves.len(), live_leaves);
+        assert_eq!(contree.find(Vec3::ZERO).unwrap().material, Some(10));
+        for i in 1..20 {
+            let expected = (i % 2 == 0).then_some(i);
+            assert_eq!(
+                contree.find(Vec3::splat(i as f32 * 6.)).unwrap().material,
+                expected
+            );
+            assert_eq!(
+                contree.find(Vec3::splat(i as f32 * -6.)).unwrap().material,
+                Some(i)
+            );
+        }
+    }
+
+    #[test]
+    fn compact_depth_first_order() {
+        let mut contree = create_contree(64, Vec3::splat(20.));
+        contree.insert(Vec3::splat(-20.), 2).unwrap();
+        contree.insert(Vec3::ZERO, 3).unwrap();
+
+        contree.compact();
+
+        // children are visited in morton order, so the most negative leaf comes first
+        let materials: Vec<_> = contree
+            .leaves
+            .iter()
+            .map(|leaf| leaf.children[leaf.contains.trailing_zeros() as usize])
+            .collect();
+        assert_eq!(materials, &[2, 3, 10]);
+        assert_eq!(contree.inners[0].child(0), Child::Inner(1));
+    }
+
+    #[test]
+    fn compact_uploads_once() {
+        let log = WriteLog::default();
+        let mut contree = Contree::new(&log).unwrap();
+        contree.size = 64;
+        contree
+            .fill_aabb(IVec3::splat(-10), IVec3::splat(10), 1)
+            .unwrap();
+        contree
+            .fill_aabb(IVec3::splat(-10), IVec3::splat(0), 0)
+            .unwrap();
+        log.clear();
+
+        contree.compact();
+
+        assert_eq!(log.inner_runs(), &[(0, contree.inners.len())]);
+        assert_eq!(log.leaf_runs(), &[(0, contree.leaves.len())]);
+    }
+}
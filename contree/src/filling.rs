@@ -0,0 +1,370 @@
+use glam::{IVec3, UVec3};
+
+use super::{AIR, Addr, Contree, ContreeError, node_management::Child, util::*};
+
+/// Box being filled, in normalized coordinates with inclusive bounds
+#[derive(Debug, Clone, Copy)]
+struct FillRegion {
+    lo: UVec3,
+    hi: UVec3,
+    material: u8,
+    emissive: bool,
+}
+
+impl FillRegion {
+    fn contains(&self, p: UVec3) -> bool {
+        self.lo.cmple(p).all() && p.cmple(self.hi).all()
+    }
+
+    fn intersects(&self, min: UVec3, size: u32) -> bool {
+        self.lo.cmple(min + (size - 1)).all() && min.cmple(self.hi).all()
+    }
+
+    fn covers(&self, min: UVec3, size: u32) -> bool {
+        self.contains(min) && self.contains(min + (size - 1))
+    }
+}
+
+impl Contree<'_> {
+    /// Set every voxel between two corners (inclusive) to a material
+    ///
+    /// Nodes entirely inside the box become solid, only nodes along its boundary are split.
+    /// Light bits are set when the material is emissive.
+    /// Filling with air clears the box and frees any nodes left empty.
+    pub fn fill_aabb(&mut self, min: IVec3, max: IVec3, material: u8) -> Result<(), ContreeError> {
+        if min.cmpgt(max).any() {
+            return Ok(());
+        }
+        if material != AIR {
+            self.grow_to_cover(min.as_vec3(), max.as_vec3())?;
+        }
+        if self.root.is_none() {
+            return Ok(());
+        }
+        let root = self.checked_root()?;
+
+        let lo = self.normalize_voxel(min).max(IVec3::ZERO);
+        let hi = self
+            .normalize_voxel(max)
+            .min(IVec3::splat(self.size as i32 - 1));
+        if lo.cmpgt(hi).any() {
+            return Ok(());
+        }
+
+        let region = FillRegion {
+            lo: lo.as_uvec3(),
+            hi: hi.as_uvec3(),
+            material,
+            emissive: self.palette.is_emissive(material),
+        };
+        self.record_aabb(
+            self.denormalize_voxel(region.lo),
+            self.denormalize_voxel(region.hi),
+            material,
+        );
+        self.fill_inner(root, UVec3::ZERO, self.size, region)?;
+        self.binding
+            .write_inner(root, &[self.inners[root as usize]]);
+
+        if material == AIR && self.shrink_on_remove {
+            self.shrink_to_fit();
+        }
+        self.check_invariants();
+        Ok(())
+    }
+
+    /// Fill the children of an inner node, uploading every changed child once
+    ///
+    /// Children entirely inside the box become solid, the node itself is left for the caller to upload.
+    /// Every node is checked before it is changed, a corrupted tree may be left partially filled.
+    fn fill_inner(
+        &mut self,
+        addr: Addr,
+        node_min: UVec3,
+        node_size: u32,
+        region: FillRegion,
+    ) -> Result<(), ContreeError> {
+        self.check_links(addr)?;
+        let child_size = node_size / 4;
+        for i in 0..64 {
+            let child_min = node_min + child_offset(i) * child_size;
+            if !region.intersects(child_min, child_size) {
+                continue;
+            }
+            let mut child = self.inners[addr as usize].child(i);
+
+            if region.covers(child_min, child_size) {
+                self.check_subtree(child)?;
+                self.free_child(child);
+                child = if region.material == AIR {
+                    Child::Empty
+                } else {
+                    Child::Solid(region.material)
+                };
+            } else {
+                child = match child {
+                    Child::Solid(material) if material == region.material => continue,
+                    Child::Solid(material) => self.expand_solid(material, child_size == 4)?,
+                    Child::Empty if region.material == AIR => continue,
+                    Child::Empty if child_size == 4 => Child::Leaf(self.alloc_leaf_node()?),
+                    Child::Empty => Child::Inner(self.alloc_inner_node()?),
+                    child => child,
+                };
+                match child {
+                    Child::Leaf(leaf_addr) => self.fill_leaf(leaf_addr, child_min, region),
+                    Child::Inner(inner_addr) => {
+                        self.fill_inner(inner_addr, child_min, child_size, region)?
+                    }
+                    _ => unreachable!("Only nodes are partially filled!"),
+                }
+
+                child = self.simplify(child);
+                match child {
+                    Child::Leaf(leaf_addr) => self
+                        .binding
+                        .write_leaf(leaf_addr, &[self.leaves[leaf_addr as usize]]),
+                    Child::Inner(inner_addr) => self
+                        .binding
+                        .write_inner(inner_addr, &[self.inners[inner_addr as usize]]),
+                    _ => {}
+                }
+            }
+
+            let lit = self.is_lit(child);
+            let node = &mut self.inners[addr as usize];
+            node.set_child(i, child);
+            node.set_light(i, lit);
+        }
+        self.update_material(addr);
+        Ok(())
+    }
+
+    /// Fill the voxels of a leaf with a single bitmask update
+    fn fill_leaf(&mut self, addr: Addr, leaf_min: UVec3, region: FillRegion) {
+        let mask = (0..64)
+            .filter(|&i| region.contains(leaf_min + child_offset(i)))
+            .fold(0, |mask, i| mask | (1 << i));
+
+        let leaf = &mut self.leaves[addr as usize];
+        if region.material == AIR {
+            leaf.contains &= !mask;
+        } else {
+            leaf.contains |= mask;
+        }
+        leaf.light &= !mask;
+        if region.emissive {
+            leaf.light |= mask;
+        }
+        for i in 0..64 {
+            if (mask >> i) & 1 == 1 {
+                leaf.children[i] = region.material;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use glam::Vec3;
+
+    use super::*;
+    use crate::test_util::{WriteLog, corrupt_root_link, create_contree};
+
+    fn material_at(contree: &Contree, p: IVec3) -> Option<u8> {
+        contree.find(p.as_vec3()).unwrap().material
+    }
+
+    #[test]
+    fn fill_whole_leaf() {
+        let mut contree = create_contree(64, Vec3::splat(-20.));
+        // normalized 32..36 on every axis is exactly one leaf
+        contree.fill_aabb(IVec3::ZERO, IVec3::splat(3), 5).unwrap();
+
+        let found = contree.find(Vec3::ZERO).unwrap();
+        assert_eq!(found.material, Some(5));
+        assert!(found.leaf_address.is_none());
+        assert_eq!(
+            contree.inners[found.parent_address as usize]
+                .child(morton_index(found.traversal_state.0, MAX_MORTON_INDEX - 1).unwrap()),
+            Child::Solid(5)
+        );
+        assert_eq!(contree.leaves.len(), 1);
+    }
+
+    #[test]
+    fn fill_fresh_tree() {
+        let mut contree = Contree::default();
+        contree.fill_aabb(IVec3::ZERO, IVec3::splat(20), 3).unwrap();
+
+        assert_eq!(contree.iter().count(), 21 * 21 * 21);
+        assert_eq!(material_at(&contree, IVec3::ZERO), Some(3));
+        assert_eq!(material_at(&contree, IVec3::splat(20)), Some(3));
+    }
+
+    #[test]
+    fn fill_large_box_is_solid() {
+        let mut contree = create_contree(256, Vec3::splat(-100.));
+        contree
+            .fill_aabb(IVec3::splat(-64), IVec3::splat(63), 3)
+            .unwrap();
+
+        // the box is 8 children of the root, with nothing below them
+        assert_eq!(contree.inners.len(), 1 + 2);
+        assert_eq!(
+            contree.inners[contree.root.unwrap() as usize]
+                .solid
+                .count_ones(),
+            8
+        );
+        assert_eq!(material_at(&contree, IVec3::new(-64, 0, 63)), Some(3));
+        assert_eq!(material_at(&contree, IVec3::new(-65, 0, 0)), None);
+        assert_eq!(contree.iter().count(), 128 * 128 * 128 + 1);
+    }
+
+    #[test]
+    fn fill_splits_solid() {
+        let mut contree = create_contree(64, Vec3::splat(-20.));
+        contree
+            .fill_aabb(IVec3::splat(-16), IVec3::splat(15), 3)
+            .unwrap();
+        contree
+            .fill_aabb(IVec3::new(0, 0, 0), IVec3::new(0, 0, 5), 4)
+            .unwrap();
+        contree
+            .fill_aabb(IVec3::new(1, 1, 1), IVec3::new(2, 2, 2), AIR)
+            .unwrap();
+
+        assert_eq!(material_at(&contree, IVec3::new(0, 0, 3)), Some(4));
+        assert_eq!(material_at(&contree, IVec3::new(1, 0, 3)), Some(3));
+        assert_eq!(material_at(&contree, IVec3::new(1, 1, 1)), None);
+        assert_eq!(material_at(&contree, IVec3::new(-16, 15, -16)), Some(3));
+
+        // putting the original material back collapses everything again
+        contree
+            .fill_aabb(IVec3::splat(-16), IVec3::splat(15), 3)
+            .unwrap();
+        assert_eq!(
+            contree.inners[contree.root.unwrap() as usize]
+                .solid
+                .count_ones(),
+            8
+        );
+        assert_eq!(contree.leaves.len() - contree.leaf_tombstones.len(), 1);
+    }
+
+    #[test]
+    fn fill_partial_box() {
+        let mut contree = create_contree(64, Vec3::splat(-20.));
+        let (min, max) = (IVec3::new(-3, 0, 2), IVec3::new(5, 1, 9));
+        contree.fill_aabb(min, max, 7).unwrap();
+
+        for x in -6..9 {
+            for y in -3..4 {
+                for z in -1..12 {
+                    let p = IVec3::new(x, y, z);
+                    let expected = if min.cmple(p).all() && p.cmple(max).all() {
+                        Some(7)
+                    } else {
+                        None
+                    };
+                    assert_eq!(material_at(&contree, p), expected, "{p}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn fill_writes_each_node_once() {
+        let log = WriteLog::default();
+        let mut contree = Contree::new(&log).unwrap();
+        contree.size = 64;
+        log.clear();
+
+        contree
+            .fill_aabb(IVec3::splat(-10), IVec3::splat(10), 1)
+            .unwrap();
+
+        let counts = |runs: Vec<(Addr, usize)>| {
+            let mut counts = HashMap::<Addr, usize>::new();
+            for (start, len) in runs {
+                for addr in start..start + len as Addr {
+                    *counts.entry(addr).or_default() += 1;
+                }
+            }
+            counts
+        };
+        let inners = counts(log.inner_runs());
+        let leaves = counts(log.leaf_runs());
+        assert!(inners.values().all(|&n| n == 1));
+        assert!(leaves.values().all(|&n| n == 1));
+        assert_eq!(leaves.len(), contree.leaves.len());
+        assert_eq!(inners.len(), contree.inners.len());
+    }
+
+    #[test]
+    fn fill_air_clears() {
+        let mut contree = create_contree(64, Vec3::splat(20.));
+        contree
+            .fill_aabb(IVec3::splat(-16), IVec3::splat(15), 2)
+            .unwrap();
+        contree
+            .fill_aabb(IVec3::splat(-16), IVec3::splat(15), AIR)
+            .unwrap();
+
+        assert_eq!(material_at(&contree, IVec3::ZERO), None);
+        assert_eq!(material_at(&contree, IVec3::splat(-16)), None);
+        assert_eq!(material_at(&contree, IVec3::splat(20)), Some(10));
+        assert_eq!(
+            contree.leaves.len() - contree.leaf_tombstones.len(),
+            1,
+            "only the leaf outside the box is left"
+        );
+    }
+
+    #[test]
+    fn fill_air_partial() {
+        let mut contree = create_contree(64, Vec3::ZERO);
+        contree
+            .fill_aabb(IVec3::splat(-2), IVec3::splat(2), 3)
+            .unwrap();
+        contree
+            .fill_aabb(IVec3::new(-2, -2, 0), IVec3::splat(2), AIR)
+            .unwrap();
+
+        assert_eq!(material_at(&contree, IVec3::new(1, 1, -1)), Some(3));
+        assert_eq!(material_at(&contree, IVec3::new(1, 1, 0)), None);
+        assert_eq!(material_at(&contree, IVec3::new(-2, 2, 2)), None);
+    }
+
+    #[test]
+    fn fill_empty_box() {
+        let mut contree = create_contree(64, Vec3::ZERO);
+        contree
+            .fill_aabb(IVec3::splat(3), IVec3::splat(2), 3)
+            .unwrap();
+        contree
+            .fill_aabb(IVec3::splat(100), IVec3::splat(200), AIR)
+            .unwrap();
+
+        assert_eq!(contree.leaves.len(), 1);
+        assert_eq!(contree.size, 64);
+    }
+
+    #[test]
+    fn fill_reports_corrupted_tree() {
+        let mut contree = create_contree(64, Vec3::ZERO);
+        let corrupted = corrupt_root_link(&mut contree);
+
+        assert_eq!(
+            contree.fill_aabb(IVec3::ZERO, IVec3::splat(2), 3),
+            Err(corrupted.clone())
+        );
+        // freeing the corrupted child is checked too
+        assert_eq!(
+            contree.fill_aabb(IVec3::splat(-32), IVec3::splat(31), AIR),
+            Err(corrupted)
+        );
+    }
+}
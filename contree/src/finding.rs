@@ -1,44 +1,91 @@
 use glam::Vec3;
 
-use super::{Addr, Contree, util::*};
+use super::{AIR, Addr, Contree, ContreeError, util::*};
 
 pub struct FindResult {
     pub material: Option<u8>,
     pub leaf_address: Option<Addr>,
     pub parent_address: Addr,
-    pub traversal_state: (u64, u8),
+    pub traversal_state: (MortonCode, u8),
     pub depth: u8,
 }
 
 impl Contree<'_> {
-    pub fn find(&self, pos: Vec3) -> Option<FindResult> {
-        let code = morton_code(self.normalize(pos));
-        let mut next_morton_index = MAX_MORTON_INDEX + 1 - (self.size.ilog2() as u8 / 2);
+    /// Find the voxel at a position, or the empty node containing it
+    ///
+    /// Fails if the position is out of bounds or the path to it is corrupted
+    pub fn find(&self, pos: Vec3) -> Result<FindResult, ContreeError> {
+        self.find_at_depth(pos, u8::MAX)
+    }
+
+    /// Find a position without descending more than `max_depth` levels below the root
+    ///
+    /// When traversal stops at a node, its representative material is returned in place of the
+    /// voxel's, with `parent_address` pointing at the node itself for inner nodes.
+    pub fn find_at_depth(&self, pos: Vec3, max_depth: u8) -> Result<FindResult, ContreeError> {
+        let code = self.locate(pos)?;
+        let mut next_morton_index = self.first_morton_index();
 
         let mut depth = 0;
-        let mut parent_address: Addr = self.root?;
+        let mut parent_address = self.checked_root()?;
 
-        while next_morton_index < MAX_MORTON_INDEX {
+        loop {
             let parent = self.inners[parent_address as usize];
+            if depth == max_depth {
+                return Ok(FindResult {
+                    material: (parent.material as u8 != AIR).then_some(parent.material as u8),
+                    leaf_address: None,
+                    parent_address,
+                    traversal_state: (code, next_morton_index),
+                    depth,
+                });
+            }
+
             let index = morton_index(code, next_morton_index).expect("Traversal iter empty!");
             let child_addr = parent.children[index as usize] as Addr;
 
             let child_exists = (parent.contains >> index) & 1 == 1;
             let child_leaf = (parent.leaf >> index) & 1 == 1;
+            let child_solid = (parent.solid >> index) & 1 == 1;
+            let corrupted = ContreeError::CorruptedLink {
+                parent: parent_address,
+                index,
+            };
 
             if child_exists {
                 depth += 1;
                 next_morton_index += 1;
 
+                // the whole child is one material, there are no nodes below it
+                if child_solid {
+                    return Ok(FindResult {
+                        material: Some(child_addr as u8),
+                        leaf_address: None,
+                        parent_address,
+                        traversal_state: (code, next_morton_index),
+                        depth,
+                    });
+                }
+
                 // leaf node contains this coordinate
                 // this does not mean that something exists at this coordinate
                 if child_leaf {
-                    let leaf = self.leaves[child_addr as usize];
+                    let leaf = *self.leaves.get(child_addr as usize).ok_or(corrupted)?;
+                    if depth == max_depth {
+                        return Ok(FindResult {
+                            material: Some(leaf.representative()),
+                            leaf_address: Some(child_addr),
+                            parent_address,
+                            traversal_state: (code, next_morton_index),
+                            depth,
+                        });
+                    }
+
                     let index =
                         morton_index(code, next_morton_index).expect("Traversal iter empty!");
                     let contains = (leaf.contains >> index) & 1 == 1;
 
-                    return Some(FindResult {
+                    return Ok(FindResult {
                         material: contains.then(|| leaf.children[index as usize]),
                         leaf_address: Some(child_addr),
                         parent_address,
@@ -47,9 +94,14 @@ impl Contree<'_> {
                     });
                 }
 
+                // only leaves and solid children sit at the bottom of the tree
+                if next_morton_index == MAX_MORTON_INDEX || child_addr as usize >= self.inners.len()
+                {
+                    return Err(corrupted);
+                }
                 parent_address = child_addr;
             } else {
-                return Some(FindResult {
+                return Ok(FindResult {
                     material: None,
                     leaf_address: None,
                     parent_address,
@@ -58,25 +110,15 @@ impl Contree<'_> {
                 });
             }
         }
-        unreachable!();
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{ContreeInner, ContreeLeaf};
+    use crate::{ContreeError, ContreeInner, ContreeLeaf};
 
     use super::*;
-
-    fn create_contree(size: u32, p: Vec3) -> Contree<'static> {
-        assert!(size > 4, "The root node cannot be a leaf!");
-        let mut contree = Contree {
-            size,
-            ..Default::default()
-        };
-        contree.insert(p, 10);
-        contree
-    }
+    use crate::test_util::create_contree;
 
     #[test]
     fn node_sizing() {
@@ -100,7 +142,7 @@ mod tests {
             traversal_state: (code, mut next_morton_index),
             parent_address,
             depth,
-        } = contree.find(Vec3::new(5., 8., 9.)).unwrap();
+        } = contree.find(Vec3::new(5., 7., 6.)).unwrap();
 
         let traversal_iter = std::iter::from_fn(|| {
             let res = morton_index(code, next_morton_index);
@@ -110,7 +152,7 @@ mod tests {
 
         assert!(material.is_none());
         assert!(leaf_address.is_none());
-        assert_eq!(traversal_iter.collect::<Vec<_>>(), &[36, 5]);
+        assert_eq!(traversal_iter.collect::<Vec<_>>(), &[63, 30]);
         assert_eq!(parent_address, 0);
         assert_eq!(depth, 0);
     }
@@ -129,14 +171,19 @@ mod tests {
             inners: vec![ContreeInner {
                 contains: 1 << 56,
                 leaf: 1 << 56,
+                solid: 0,
                 light: 0,
+                material: 10,
+                padding: 0,
                 children: inner_children,
-            }],
+            }]
+            .into(),
             leaves: vec![ContreeLeaf {
                 contains: 1 << 0,
                 light: 0,
                 children: leaf_children,
-            }],
+            }]
+            .into(),
             ..Default::default()
         };
 
@@ -207,15 +254,43 @@ mod tests {
     fn find_out_of_bounds() {
         let contree = Contree::default();
         let p = Vec3::splat(contree.size as f32);
-        let FindResult {
-            leaf_address,
-            parent_address,
-            depth,
-            ..
-        } = contree.find(p).unwrap();
 
-        assert_eq!(leaf_address, None);
-        assert_eq!(parent_address, 0);
-        assert_eq!(depth, 0);
+        assert_eq!(contree.find(p).err(), Some(ContreeError::OutOfRange(p)));
+        assert!(matches!(
+            contree.find(Vec3::NAN),
+            Err(ContreeError::OutOfRange(_))
+        ));
+    }
+
+    #[test]
+    fn find_corrupted_link() {
+        let p = Vec3::splat(3.);
+        let mut contree = create_contree(64, p);
+        let FindResult { parent_address, .. } = contree.find(p).unwrap();
+        let root = contree.root.unwrap();
+        let index = (0..64)
+            .find(|&i| (contree.inners[root as usize].contains >> i) & 1 == 1)
+            .unwrap();
+        contree.inners[root as usize].children[index as usize] = 1000;
+
+        assert_eq!(
+            contree.find(p).err(),
+            Some(ContreeError::CorruptedLink {
+                parent: root,
+                index
+            })
+        );
+        assert_ne!(parent_address, root);
+    }
+
+    #[test]
+    fn find_invalid_size() {
+        let mut contree = create_contree(64, Vec3::ZERO);
+        contree.size = 32;
+
+        assert_eq!(
+            contree.find(Vec3::ZERO).err(),
+            Some(ContreeError::InvalidSize(32))
+        );
     }
 }
@@ -0,0 +1,199 @@
+use glam::{IVec3, UVec3};
+
+use super::{Addr, Contree, node_management::Child, util::*};
+
+/// Inner node partway through being visited
+#[derive(Debug, Clone, Copy)]
+struct Frame {
+    addr: Addr,
+    min: UVec3,
+    size: u32,
+    next: u8,
+    /// Part of a solid node, every child is this material
+    solid: Option<u8>,
+}
+
+/// Depth-first walk over the voxels of a tree, in morton order
+///
+/// `visit` receives the normalized minimum corner and size of every node and voxel,
+/// anything it rejects is skipped along with everything below it.
+pub(crate) struct Voxels<'c, 'a, F> {
+    contree: &'c Contree<'a>,
+    visit: F,
+    /// Follow the light masks instead, only visiting light sources
+    lights_only: bool,
+    stack: Vec<Frame>,
+    leaf: Option<(Addr, UVec3, u8)>,
+}
+
+impl<'c, 'a, F: FnMut(UVec3, u32) -> bool> Voxels<'c, 'a, F> {
+    pub(crate) fn new(contree: &'c Contree<'a>, visit: F) -> Self {
+        let stack = contree
+            .root
+            .map(|addr| Frame {
+                addr,
+                min: UVec3::ZERO,
+                size: contree.size,
+                next: 0,
+                solid: None,
+            })
+            .into_iter()
+            .collect();
+
+        Self {
+            contree,
+            visit,
+            lights_only: false,
+            stack,
+            leaf: None,
+        }
+    }
+
+    /// Walk only the voxels of emissive materials, skipping nodes without any
+    pub(crate) fn lights(contree: &'c Contree<'a>, visit: F) -> Self {
+        Self {
+            lights_only: true,
+            ..Self::new(contree, visit)
+        }
+    }
+}
+
+impl<F: FnMut(UVec3, u32) -> bool> Iterator for Voxels<'_, '_, F> {
+    type Item = (IVec3, u8);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((addr, min, next)) = &mut self.leaf {
+                let leaf = &self.contree.leaves[*addr as usize];
+                let mask = if self.lights_only {
+                    leaf.light
+                } else {
+                    leaf.contains
+                };
+                while *next < 64 {
+                    let i = *next;
+                    *next += 1;
+
+                    let p = *min + child_offset(i);
+                    if (mask >> i) & 1 == 1 && (self.visit)(p, 1) {
+                        return Some((
+                            self.contree.denormalize_voxel(p),
+                            leaf.children[i as usize],
+                        ));
+                    }
+                }
+                self.leaf = None;
+            }
+
+            let frame = self.stack.last_mut()?;
+            if frame.next == 64 {
+                self.stack.pop();
+                continue;
+            }
+            let i = frame.next;
+            frame.next += 1;
+
+            let size = frame.size / 4;
+            let min = frame.min + child_offset(i) * size;
+            let (child, lit) = match frame.solid {
+                Some(material) => (Child::Solid(material), true),
+                None => {
+                    let node = &self.contree.inners[frame.addr as usize];
+                    (node.child(i), (node.light >> i) & 1 == 1)
+                }
+            };
+            match child {
+                Child::Empty => {}
+                _ if self.lights_only && !lit => {}
+                _ if !(self.visit)(min, size) => {}
+                Child::Solid(material) if size == 1 => {
+                    return Some((self.contree.denormalize_voxel(min), material));
+                }
+                Child::Solid(material) => self.stack.push(Frame {
+                    addr: 0,
+                    min,
+                    size,
+                    next: 0,
+                    solid: Some(material),
+                }),
+                Child::Inner(addr) => self.stack.push(Frame {
+                    addr,
+                    min,
+                    size,
+                    next: 0,
+                    solid: None,
+                }),
+                Child::Leaf(addr) => self.leaf = Some((addr, min, 0)),
+            }
+        }
+    }
+}
+
+impl Contree<'_> {
+    /// Every occupied voxel and its material, in morton order
+    pub fn iter(&self) -> impl Iterator<Item = (IVec3, u8)> + '_ {
+        Voxels::new(self, |_, _| true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::{DVec3, Vec3};
+
+    use super::*;
+    use crate::test_util::create_contree;
+
+    #[test]
+    fn iter_single() {
+        let contree = create_contree(64, Vec3::new(-3., 5., 20.));
+
+        assert_eq!(
+            contree.iter().collect::<Vec<_>>(),
+            &[(IVec3::new(-3, 5, 20), 10)]
+        );
+    }
+
+    #[test]
+    fn iter_empty() {
+        let mut contree = create_contree(64, Vec3::ZERO);
+        contree.remove(Vec3::ZERO).unwrap();
+
+        assert_eq!(contree.iter().count(), 0);
+    }
+
+    #[test]
+    fn iter_morton_order() {
+        let mut contree = Contree {
+            size: 256,
+            center_offset: DVec3::new(32., -16., 4.),
+            ..Default::default()
+        };
+        let mut expected = Vec::new();
+        for i in -20..20 {
+            let p = IVec3::new(i * 4, i * -3, (i * i) % 40);
+            contree.insert(p.as_vec3(), i as u8).unwrap();
+            expected.retain(|(q, _)| *q != p);
+            expected.push((p, i as u8));
+        }
+        expected.sort_by_key(|(p, _)| morton_code(contree.normalize(p.as_vec3())));
+
+        assert_eq!(contree.size, 256);
+        assert_eq!(contree.iter().collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn iter_filled_box() {
+        let mut contree = create_contree(64, Vec3::splat(-30.));
+        contree
+            .fill_aabb(IVec3::new(-2, 0, 3), IVec3::new(4, 9, 5), 2)
+            .unwrap();
+
+        let voxels: Vec<_> = contree.iter().collect();
+        assert_eq!(voxels.len(), 7 * 10 * 3 + 1);
+        assert!(voxels.iter().filter(|(p, _)| *p != IVec3::splat(-30)).all(
+            |&(p, material)| material == 2
+                && p.cmpge(IVec3::new(-2, 0, 3)).all()
+                && p.cmple(IVec3::new(4, 9, 5)).all()
+        ));
+    }
+}
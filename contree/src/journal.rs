@@ -0,0 +1,425 @@
+use glam::{IVec3, Vec3};
+use serde::{Deserialize, Serialize};
+
+use super::{AIR, Contree, ContreeError};
+
+/// Bytes of changes a new [`Journal`] keeps before dropping its oldest transactions
+pub const DEFAULT_HISTORY_BYTES: usize = 16 << 20;
+
+/// One voxel changed by a transaction, in world voxel coordinates
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct VoxelChange {
+    #[serde(with = "voxel_pos")]
+    pub pos: IVec3,
+    pub old: u8,
+    pub new: u8,
+}
+
+/// glam's tuple struct encoding does not roundtrip through serde-binary
+mod voxel_pos {
+    use glam::IVec3;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(pos: &IVec3, serializer: S) -> Result<S::Ok, S::Error> {
+        pos.to_array().serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<IVec3, D::Error> {
+        <[i32; 3]>::deserialize(deserializer).map(IVec3::from_array)
+    }
+}
+
+/// Edit history of a tree, see [`Contree::begin_transaction`]
+///
+/// Committed transactions are undone newest first, undoing moves them onto the redo stack
+/// until the next commit clears it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Journal {
+    undo: Vec<Vec<VoxelChange>>,
+    redo: Vec<Vec<VoxelChange>>,
+    /// Changes of the open transaction in the order they were made, positions may repeat
+    pending: Option<Vec<VoxelChange>>,
+    /// The open transaction outgrew `max_bytes` and its changes were dropped
+    overflowed: bool,
+    /// Most bytes of changes kept, the oldest transactions are dropped past it
+    ///
+    /// A transaction that outgrows it on its own cannot be undone, so committing it drops the
+    /// whole history.
+    pub max_bytes: usize,
+}
+
+impl Default for Journal {
+    fn default() -> Self {
+        Self {
+            undo: Vec::new(),
+            redo: Vec::new(),
+            pending: None,
+            overflowed: false,
+            max_bytes: DEFAULT_HISTORY_BYTES,
+        }
+    }
+}
+
+impl Journal {
+    /// Whether a transaction is open and edits are being recorded
+    pub fn is_recording(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    /// Transactions that can be undone
+    pub fn undo_len(&self) -> usize {
+        self.undo.len()
+    }
+
+    /// Transactions that can be redone
+    pub fn redo_len(&self) -> usize {
+        self.redo.len()
+    }
+
+    /// Bytes held by the committed transactions
+    pub fn bytes(&self) -> usize {
+        self.undo
+            .iter()
+            .chain(&self.redo)
+            .map(Vec::len)
+            .sum::<usize>()
+            * size_of::<VoxelChange>()
+    }
+
+    /// Forget every committed transaction, an open one keeps recording
+    pub fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+    }
+
+    /// Whether edits of the open transaction still need to be recorded
+    fn accepts_changes(&self) -> bool {
+        self.pending.is_some() && !self.overflowed
+    }
+
+    /// Changes the open transaction can record before outgrowing `max_bytes`
+    fn capacity(&self) -> usize {
+        let recorded = self.pending.as_ref().map_or(0, Vec::len);
+        (self.max_bytes / size_of::<VoxelChange>()).saturating_sub(recorded)
+    }
+
+    fn record(&mut self, pos: IVec3, old: u8, new: u8) {
+        if !self.accepts_changes() {
+            return;
+        }
+        if let Some(pending) = &mut self.pending {
+            pending.push(VoxelChange { pos, old, new });
+            if pending.len() * size_of::<VoxelChange>() > self.max_bytes {
+                self.overflow();
+            }
+        }
+    }
+
+    /// Drop the changes of the open transaction and stop recording until it is committed
+    fn overflow(&mut self) {
+        self.pending = Some(Vec::new());
+        self.overflowed = true;
+    }
+
+    /// Close the open transaction, keeping one change per voxel that ended up different
+    fn commit(&mut self) -> usize {
+        let Some(mut pending) = self.pending.take() else {
+            return 0;
+        };
+        if std::mem::take(&mut self.overflowed) {
+            // older transactions no longer undo into states that existed
+            self.clear();
+            return 0;
+        }
+        // stable so each voxel's changes stay in the order they were made
+        pending.sort_by_key(|change| change.pos.to_array());
+        let mut changes: Vec<VoxelChange> = Vec::new();
+        for change in pending {
+            match changes.last_mut() {
+                Some(last) if last.pos == change.pos => last.new = change.new,
+                _ => changes.push(change),
+            }
+        }
+        changes.retain(|change| change.old != change.new);
+        if changes.is_empty() {
+            return 0;
+        }
+
+        let len = changes.len();
+        self.undo.push(changes);
+        self.redo.clear();
+        while self.undo.len() > 1 && self.bytes() > self.max_bytes {
+            self.undo.remove(0);
+        }
+        len
+    }
+}
+
+impl Contree<'_> {
+    /// Start recording edits into a transaction, which can be undone once committed
+    ///
+    /// Does nothing if a transaction is already open.
+    pub fn begin_transaction(&mut self) {
+        if self.journal.pending.is_none() {
+            self.journal.pending = Some(Vec::new());
+        }
+    }
+
+    /// Close the open transaction, returning how many voxels it changed
+    ///
+    /// Transactions that changed nothing are not kept. One that outgrew [`Journal::max_bytes`]
+    /// returns 0 and drops the whole history, as nothing before it can be undone correctly.
+    pub fn commit_transaction(&mut self) -> usize {
+        self.journal.commit()
+    }
+
+    /// Revert the newest committed transaction, committing any open one first
+    ///
+    /// Returns false if there was nothing to undo.
+    /// The changes go through the regular edits, so they are uploaded like any other.
+    pub fn undo(&mut self) -> Result<bool, ContreeError> {
+        self.commit_transaction();
+        let Some(changes) = self.journal.undo.pop() else {
+            return Ok(false);
+        };
+        for change in &changes {
+            self.set(change.pos.as_vec3(), change.old)?;
+        }
+        self.journal.redo.push(changes);
+        Ok(true)
+    }
+
+    /// Apply the newest undone transaction again, committing any open one first
+    ///
+    /// Returns false if there was nothing to redo, committing a non-empty transaction
+    /// clears everything that could be redone.
+    pub fn redo(&mut self) -> Result<bool, ContreeError> {
+        self.commit_transaction();
+        let Some(changes) = self.journal.redo.pop() else {
+            return Ok(false);
+        };
+        for change in &changes {
+            self.set(change.pos.as_vec3(), change.new)?;
+        }
+        self.journal.undo.push(changes);
+        Ok(true)
+    }
+
+    /// Record the voxel at an in bounds position before an edit sets it to `new`
+    pub(super) fn record(&mut self, pos: Vec3, new: u8) -> Result<(), ContreeError> {
+        if !self.journal.accepts_changes() {
+            return Ok(());
+        }
+        let old = self.find(pos)?.material.unwrap_or(AIR);
+        let voxel = self.denormalize_voxel(self.normalize(pos));
+        self.journal.record(voxel, old, new);
+        Ok(())
+    }
+
+    /// Record every voxel between two in bounds corners (inclusive) before a fill
+    ///
+    /// Stops as soon as the transaction outgrows [`Journal::max_bytes`], so filling huge boxes
+    /// never lists more voxels than the history may hold.
+    pub(super) fn record_aabb(&mut self, min: IVec3, max: IVec3, new: u8) {
+        if !self.journal.accepts_changes() {
+            return;
+        }
+        let capacity = self.journal.capacity();
+        // voxels that were empty only change when filling with something
+        let extent = (max - min + 1).as_u64vec3();
+        let volume = extent.x as u128 * extent.y as u128 * extent.z as u128;
+        if new != AIR && volume > capacity as u128 {
+            self.journal.overflow();
+            return;
+        }
+
+        let old: Vec<(IVec3, u8)> = self
+            .query_aabb(min, max)
+            .take(capacity.saturating_add(1))
+            .collect();
+        for &(pos, material) in &old {
+            self.journal.record(pos, material, new);
+        }
+        if new != AIR && self.journal.accepts_changes() {
+            for z in min.z..=max.z {
+                for y in min.y..=max.y {
+                    for x in min.x..=max.x {
+                        self.journal.record(IVec3::new(x, y, z), AIR, new);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DummyBinding;
+    use crate::test_util::create_contree;
+    use crate::util::MAX_SIZE;
+
+    fn snapshot(contree: &Contree) -> Vec<(IVec3, u8)> {
+        contree.iter().collect()
+    }
+
+    #[test]
+    fn undo_redo_edits() {
+        let mut contree = create_contree(64, Vec3::ZERO);
+        let before = snapshot(&contree);
+
+        contree.begin_transaction();
+        contree.insert(Vec3::new(1., 2., 3.), 4).unwrap();
+        contree.insert(Vec3::ZERO, 5).unwrap();
+        contree.remove(Vec3::new(1., 2., 3.)).unwrap();
+        contree.insert(Vec3::new(-5., 0., 0.), 6).unwrap();
+        contree
+            .fill_aabb(IVec3::new(2, 2, 2), IVec3::new(5, 5, 5), 7)
+            .unwrap();
+        assert_eq!(contree.commit_transaction(), 2 + 64);
+        let after = snapshot(&contree);
+
+        assert!(contree.undo().unwrap());
+        assert_eq!(snapshot(&contree), before);
+        assert!(!contree.undo().unwrap());
+
+        assert!(contree.redo().unwrap());
+        assert_eq!(snapshot(&contree), after);
+        assert!(!contree.redo().unwrap());
+    }
+
+    #[test]
+    fn undo_fill_with_air() {
+        let mut contree = create_contree(64, Vec3::ZERO);
+        contree
+            .fill_aabb(IVec3::splat(-8), IVec3::splat(7), 3)
+            .unwrap();
+        let before = snapshot(&contree);
+
+        contree.begin_transaction();
+        contree
+            .fill_aabb(IVec3::splat(-20), IVec3::splat(20), AIR)
+            .unwrap();
+        assert_eq!(contree.commit_transaction(), 16 * 16 * 16);
+        assert!(contree.iter().next().is_none());
+
+        contree.undo().unwrap();
+        assert_eq!(snapshot(&contree), before);
+    }
+
+    #[test]
+    fn undo_growth() {
+        let mut contree = create_contree(16, Vec3::ZERO);
+        let far = Vec3::new(300., -100., 50.);
+
+        contree.begin_transaction();
+        contree
+            .insert_many([(far, 2), (Vec3::ZERO, 3), (far, 4)])
+            .unwrap();
+        assert_eq!(contree.commit_transaction(), 2);
+
+        contree.undo().unwrap();
+        assert_eq!(snapshot(&contree), vec![(IVec3::ZERO, 10)]);
+        contree.redo().unwrap();
+        assert_eq!(contree.find(far).unwrap().material, Some(4));
+        assert_eq!(contree.find(Vec3::ZERO).unwrap().material, Some(3));
+    }
+
+    #[test]
+    fn commit_clears_redo() {
+        let mut contree = create_contree(64, Vec3::ZERO);
+        for i in 1..4 {
+            contree.begin_transaction();
+            contree.insert(Vec3::splat(i as f32), i).unwrap();
+            contree.commit_transaction();
+        }
+        contree.undo().unwrap();
+        contree.undo().unwrap();
+        assert_eq!(contree.journal.redo_len(), 2);
+
+        // changing nothing keeps the redo stack
+        contree.begin_transaction();
+        contree.insert(Vec3::ZERO, 10).unwrap();
+        assert_eq!(contree.commit_transaction(), 0);
+        assert_eq!(contree.journal.redo_len(), 2);
+
+        contree.begin_transaction();
+        contree.insert(Vec3::splat(5.), 5).unwrap();
+        contree.commit_transaction();
+        assert_eq!(contree.journal.redo_len(), 0);
+        assert_eq!(contree.journal.undo_len(), 2);
+    }
+
+    #[test]
+    fn history_limited_by_memory() {
+        let mut contree = create_contree(64, Vec3::ZERO);
+        contree.journal.max_bytes = 3 * size_of::<VoxelChange>();
+        for i in 1..6 {
+            contree.begin_transaction();
+            contree.insert(Vec3::splat(i as f32), i).unwrap();
+            contree.commit_transaction();
+        }
+        assert_eq!(contree.journal.undo_len(), 3);
+
+        // a transaction past the limit on its own drops the whole history
+        contree.begin_transaction();
+        contree
+            .fill_aabb(IVec3::splat(-4), IVec3::splat(-3), 1)
+            .unwrap();
+        assert_eq!(contree.commit_transaction(), 0);
+        assert_eq!(contree.journal.undo_len(), 0);
+        assert_eq!(contree.find(Vec3::splat(-4.)).unwrap().material, Some(1));
+    }
+
+    #[test]
+    fn huge_fills_stop_recording() {
+        let mut contree = create_contree(MAX_SIZE, Vec3::ZERO);
+        contree.journal.max_bytes = 1000 * size_of::<VoxelChange>();
+        contree.begin_transaction();
+        contree.insert(Vec3::ONE, 2).unwrap();
+        contree.commit_transaction();
+
+        // far more voxels than fit in memory, filled solid nodes and then emptied again
+        let (min, max) = (IVec3::splat(-1 << 20), IVec3::splat((1 << 20) - 1));
+        for material in [3, AIR] {
+            contree.begin_transaction();
+            contree.fill_aabb(min, max, material).unwrap();
+            assert!(contree.journal.pending.as_ref().unwrap().is_empty());
+            assert_eq!(contree.commit_transaction(), 0);
+            assert_eq!(contree.journal.undo_len(), 0);
+        }
+        assert!(contree.iter().next().is_none());
+
+        // the next transaction records again
+        contree.begin_transaction();
+        contree.insert(Vec3::ONE, 2).unwrap();
+        assert_eq!(contree.commit_transaction(), 1);
+        assert!(contree.undo().unwrap());
+    }
+
+    #[test]
+    fn untracked_edits_are_not_recorded() {
+        let mut contree = create_contree(64, Vec3::ZERO);
+        contree.insert(Vec3::ONE, 2).unwrap();
+
+        assert!(!contree.journal.is_recording());
+        assert!(!contree.undo().unwrap());
+        assert_eq!(contree.find(Vec3::ONE).unwrap().material, Some(2));
+    }
+
+    #[test]
+    fn journal_survives_save_load() {
+        let mut contree = create_contree(64, Vec3::ZERO);
+        contree.begin_transaction();
+        contree.insert(Vec3::new(3., -4., 5.), 6).unwrap();
+        contree.commit_transaction();
+
+        let mut file = Vec::new();
+        contree.save(&mut file).unwrap();
+        let mut loaded = Contree::load(file.as_slice(), &DummyBinding).unwrap();
+        assert_eq!(loaded.journal, contree.journal);
+
+        loaded.undo().unwrap();
+        assert_eq!(snapshot(&loaded), vec![(IVec3::ZERO, 10)]);
+    }
+}
@@ -1,17 +1,57 @@
 use bytemuck::{Pod, Zeroable};
 use serde::{Deserialize, Serialize};
 
+pub mod arena;
+mod batching;
+mod bulk_insertion;
+mod compaction;
+mod filling;
 mod finding;
+mod iteration;
+pub mod journal;
+mod lighting;
+mod lod;
 mod node_insertion;
 mod node_management;
-mod raycasting;
+mod node_removal;
+pub mod palette;
+mod querying;
+pub mod raycasting;
+#[cfg(any(test, feature = "testing"))]
+mod recording;
+mod snapshot;
+pub mod stats;
+#[cfg(test)]
+mod test_util;
 pub mod util;
-
-use glam::Vec3;
-
-pub trait GPUBindable: std::fmt::Debug {
+pub mod validation;
+pub mod world_file;
+
+use arena::Arena;
+pub use batching::BatchingBinding;
+use glam::{DVec3, Vec3};
+use journal::Journal;
+use palette::Palette;
+#[cfg(any(test, feature = "testing"))]
+pub use recording::RecordingBinding;
+pub use snapshot::ContreeSnapshot;
+use util::MAX_SIZE;
+
+pub trait GPUBindable: std::fmt::Debug + Sync {
     fn write_inner(&self, addr: Addr, data: &[ContreeInner]);
     fn write_leaf(&self, addr: Addr, data: &[ContreeLeaf]);
+    /// Upload the whole palette, indexed by material
+    fn write_materials(&self, _data: &[Material]) {}
+    /// The root, size or center of the tree changed
+    fn bounds_changed(&self, _root: Option<Addr>, _size: u32, _center_offset: DVec3) {}
+    /// An arena grew, nodes are about to be written up to the new lengths
+    fn arenas_grown(&self, _inners: usize, _leaves: usize) {}
+    /// An inner node was freed, its address is reused by a later allocation
+    fn free_inner(&self, _addr: Addr) {}
+    /// A leaf node was freed, its address is reused by a later allocation
+    fn free_leaf(&self, _addr: Addr) {}
+    /// Every node is about to be uploaded again, anything written before can be dropped
+    fn resync(&self) {}
 }
 
 #[derive(Debug, Clone, Default)]
@@ -31,52 +71,91 @@ pub struct ContreeLeaf {
     pub children: [u8; 64],
 }
 
-// 280 bytes
+// 296 bytes
 #[repr(C, align(4))]
 #[derive(Debug, Clone, Copy, Pod, Zeroable, Serialize, Deserialize)]
 pub struct ContreeInner {
     pub contains: u64,
     pub leaf: u64,
+    /// Children completely filled with one material, whose index is stored in place of an address
+    pub solid: u64,
     pub light: u64,
+    /// Most common material among the children, drawn in place of the node from far away
+    pub material: u32,
+    pub padding: u32,
     #[serde(with = "serde_arrays")]
     pub children: [Addr; 64],
 }
 
 #[repr(C, align(16))]
-#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable, Serialize, Deserialize)]
+#[derive(
+    Debug, Clone, Copy, PartialEq, bytemuck::Pod, bytemuck::Zeroable, Serialize, Deserialize,
+)]
 pub struct Material {
     pub color: [f32; 4],
     pub reflectivity: f32,
-    pub padding: [u8; 12],
+    /// Brightness of the light given off, voxels of materials above zero are light sources
+    pub emission: f32,
+    pub padding: [u8; 8],
 }
 
+/// Material index of empty space
+pub const AIR: u8 = 0;
+
 type ChildIndex = u8;
 
 /// Address in terms of data type, not bytes
 /// Byte address = Addr * sizeof(node)
 pub type Addr = u32;
 
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum ContreeError {
+    #[error("position {0} is out of range")]
+    OutOfRange(Vec3),
+    #[error("child {index} of node {parent} does not point at a valid node")]
+    CorruptedLink { parent: Addr, index: ChildIndex },
+    #[error("node arena is full, addresses are limited to {}", Addr::MAX)]
+    ArenaFull,
+    #[error("tree size {0} is not a power of 4 between 16 and {MAX_SIZE}")]
+    InvalidSize(u32),
+    #[error("tree has no root node")]
+    NoRoot,
+}
+
 #[derive(Debug)]
 pub struct Contree<'a> {
-    pub center_offset: Vec3,
+    /// Center of the tree, f64 so voxel positions stay exact in the largest trees
+    pub center_offset: DVec3,
     pub root: Option<Addr>,
     /// Distance from face to face
     pub size: u32,
-    pub inners: Vec<ContreeInner>,
-    pub leaves: Vec<ContreeLeaf>,
+    /// Shared with snapshots until written to, see [`Contree::snapshot`]
+    pub inners: Arena<ContreeInner>,
+    pub leaves: Arena<ContreeLeaf>,
     pub inner_tombstones: Vec<Addr>,
     pub leaf_tombstones: Vec<Addr>,
+    /// Run [`Contree::shrink_to_fit`] whenever a removal frees nodes
+    pub shrink_on_remove: bool,
+    /// Most levels below the root the tree may grow to, see [`Contree::max_size`]
+    pub max_depth: u8,
+    /// Materials of the voxels, see [`Contree::set_palette`] to change it
+    pub palette: Palette,
+    /// Undo history, see [`Contree::begin_transaction`]
+    pub journal: Journal,
     pub binding: &'a dyn GPUBindable,
 }
 
 impl Default for Contree<'_> {
     fn default() -> Self {
-        Self::new(&DummyBinding)
+        Self::new(&DummyBinding).expect("An empty arena has room for the root!")
     }
 }
 
 impl<'a> Contree<'a> {
-    pub fn new(binding: &'a dyn GPUBindable) -> Self {
+    /// Empty tree of size 16 around the origin, uploading its root through the binding
+    ///
+    /// Fails if the root node cannot be allocated
+    pub fn new(binding: &'a dyn GPUBindable) -> Result<Self, ContreeError> {
         let mut new = Self {
             center_offset: Default::default(),
             root: None,
@@ -85,10 +164,16 @@ impl<'a> Contree<'a> {
             leaves: Default::default(),
             inner_tombstones: Default::default(),
             leaf_tombstones: Default::default(),
+            shrink_on_remove: false,
+            max_depth: util::MAX_MORTON_INDEX,
+            palette: Palette::default(),
+            journal: Journal::default(),
             binding,
         };
-        new.root = Some(new.create_root_node());
-        new
+        new.root = Some(new.create_root_node()?);
+        new.write_bounds();
+        new.write_palette();
+        Ok(new)
     }
 }
 
@@ -107,7 +192,13 @@ impl std::fmt::Display for Contree<'_> {
                 let cur = self.inners[addr as usize];
                 for i in 0..64 {
                     if (cur.contains & (0b1 << i)) != 0 {
-                        if (cur.leaf & (0b1 << i)) != 0 {
+                        if (cur.solid & (0b1 << i)) != 0 {
+                            writeln!(
+                                f,
+                                "\t{} -> \"mat {}\" [label=<{}>]",
+                                addr, cur.children[i], i
+                            )?;
+                        } else if (cur.leaf & (0b1 << i)) != 0 {
                             writeln!(
                                 f,
                                 "\t{} -> \"leaf {}\" [label=<{}>]",
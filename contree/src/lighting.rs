@@ -0,0 +1,235 @@
+use std::collections::BTreeSet;
+
+use glam::IVec3;
+
+use super::{Addr, Contree, iteration::Voxels, node_management::Child};
+
+impl Contree<'_> {
+    /// Whether a child has any light sources below it
+    pub(super) fn is_lit(&self, child: Child) -> bool {
+        match child {
+            Child::Empty => false,
+            Child::Inner(addr) => self.inners[addr as usize].light != 0,
+            Child::Leaf(addr) => self.leaves[addr as usize].light != 0,
+            Child::Solid(material) => self.palette.is_emissive(material),
+        }
+    }
+
+    /// Recompute every light bit in the tree from the palette
+    ///
+    /// Needed whenever a material starts or stops being emissive, changed nodes are uploaded
+    /// as contiguous ranges.
+    pub fn refresh_lights(&mut self) {
+        let Some(root) = self.root else {
+            return;
+        };
+
+        let emissive: [bool; 256] = std::array::from_fn(|i| self.palette.is_emissive(i as u8));
+        let mut inners = BTreeSet::new();
+        let mut leaves = BTreeSet::new();
+        self.relight(root, &emissive, &mut inners, &mut leaves);
+        self.write_ranges(&inners, &leaves);
+        self.check_invariants();
+    }
+
+    fn relight(
+        &mut self,
+        addr: Addr,
+        emissive: &[bool; 256],
+        inners: &mut BTreeSet<Addr>,
+        leaves: &mut BTreeSet<Addr>,
+    ) -> bool {
+        let light = self.inners[addr as usize].light;
+        for i in 0..64 {
+            let lit = match self.inners[addr as usize].child(i) {
+                Child::Empty => false,
+                Child::Solid(material) => emissive[material as usize],
+                Child::Inner(child) => self.relight(child, emissive, inners, leaves),
+                Child::Leaf(child) => {
+                    let leaf = &mut self.leaves[child as usize];
+                    let light = (0..64)
+                        .filter(|&j| {
+                            (leaf.contains >> j) & 1 == 1 && emissive[leaf.children[j] as usize]
+                        })
+                        .fold(0, |mask, j| mask | (1 << j));
+                    if leaf.light != light {
+                        leaf.light = light;
+                        leaves.insert(child);
+                    }
+                    light != 0
+                }
+            };
+            self.inners[addr as usize].set_light(i, lit);
+        }
+
+        let node = self.inners[addr as usize];
+        if node.light != light {
+            inners.insert(addr);
+        }
+        node.light != 0
+    }
+
+    /// Every light source between two corners (inclusive) and its material, in morton order
+    ///
+    /// Only nodes marked as holding lights are visited, so this is cheap even in dense regions
+    pub fn lights_in_aabb(&self, min: IVec3, max: IVec3) -> impl Iterator<Item = (IVec3, u8)> + '_ {
+        let lo = self.normalize_voxel(min);
+        let hi = self.normalize_voxel(max);
+        Voxels::lights(self, move |node_min, size| {
+            let node_min = node_min.as_ivec3();
+            node_min.cmple(hi).all() && (node_min + (size as i32 - 1)).cmpge(lo).all()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::Vec3;
+
+    use super::*;
+    use crate::Material;
+    use crate::test_util::create_contree;
+
+    /// Tree with stone at 1 and a lamp at 2
+    fn lit_contree() -> Contree<'static> {
+        let mut contree = create_contree(256, Vec3::ZERO);
+        contree
+            .add_material("stone", Material::new([0.5; 4], 0.))
+            .unwrap();
+        contree
+            .add_material("lamp", Material::new([1.; 4], 0.).with_emission(2.))
+            .unwrap();
+        contree
+    }
+
+    /// Check that every light bit matches the materials below it
+    fn assert_lights_consistent(contree: &Contree) {
+        fn check(contree: &Contree, addr: Addr) -> bool {
+            let node = contree.inners[addr as usize];
+            for i in 0..64 {
+                let lit = match node.child(i) {
+                    Child::Empty => false,
+                    Child::Solid(material) => contree.palette.is_emissive(material),
+                    Child::Inner(child) => check(contree, child),
+                    Child::Leaf(child) => {
+                        let leaf = contree.leaves[child as usize];
+                        for j in 0..64 {
+                            let expected = (leaf.contains >> j) & 1 == 1
+                                && contree.palette.is_emissive(leaf.children[j]);
+                            assert_eq!((leaf.light >> j) & 1 == 1, expected);
+                        }
+                        leaf.light != 0
+                    }
+                };
+                assert_eq!((node.light >> i) & 1 == 1, lit, "node {addr} child {i}");
+            }
+            node.light != 0
+        }
+        if let Some(root) = contree.root {
+            check(contree, root);
+        }
+    }
+
+    #[test]
+    fn insert_light_propagates() {
+        let mut contree = lit_contree();
+        contree.insert(Vec3::new(5., -40., 70.), 1).unwrap();
+        assert_eq!(contree.inners[contree.root.unwrap() as usize].light, 0);
+
+        contree.insert(Vec3::new(-60., 3., 9.), 2).unwrap();
+        assert_ne!(contree.inners[contree.root.unwrap() as usize].light, 0);
+        assert_lights_consistent(&contree);
+
+        // overwriting a light with a plain material turns it off again
+        contree.insert(Vec3::new(-60., 3., 9.), 1).unwrap();
+        assert_eq!(contree.inners[contree.root.unwrap() as usize].light, 0);
+        assert_lights_consistent(&contree);
+    }
+
+    #[test]
+    fn remove_light_propagates() {
+        let mut contree = lit_contree();
+        contree.insert(Vec3::new(-60., 3., 9.), 2).unwrap();
+        contree.insert(Vec3::new(-60., 3., 10.), 1).unwrap();
+        contree.insert(Vec3::new(40., 40., 40.), 2).unwrap();
+
+        contree.remove(Vec3::new(-60., 3., 9.)).unwrap();
+        assert_lights_consistent(&contree);
+        contree.remove(Vec3::new(40., 40., 40.)).unwrap();
+        assert_lights_consistent(&contree);
+        assert_eq!(contree.inners[contree.root.unwrap() as usize].light, 0);
+    }
+
+    #[test]
+    fn fill_and_bulk_insert_lights() {
+        let mut contree = lit_contree();
+        contree
+            .fill_aabb(IVec3::new(-20, -3, -20), IVec3::new(20, -1, 20), 1)
+            .unwrap();
+        contree
+            .fill_aabb(IVec3::new(-2, 0, -2), IVec3::new(2, 0, 2), 2)
+            .unwrap();
+        contree
+            .insert_many((0..50).map(|i| {
+                let p = Vec3::new(i as f32 * 2. - 50., 30., i as f32);
+                (p, if i % 3 == 0 { 2 } else { 1 })
+            }))
+            .unwrap();
+        assert_lights_consistent(&contree);
+
+        contree
+            .fill_aabb(IVec3::new(-2, 0, -2), IVec3::new(0, 0, 2), 0)
+            .unwrap();
+        assert_lights_consistent(&contree);
+        assert_eq!(
+            contree
+                .lights_in_aabb(IVec3::new(-20, -3, -20), IVec3::new(20, 0, 20))
+                .count(),
+            2 * 5
+        );
+    }
+
+    #[test]
+    fn lights_in_aabb_matches_filter() {
+        let mut contree = lit_contree();
+        for i in -40..40 {
+            let p = Vec3::new((i * 3) as f32, ((i * 7) % 50) as f32, ((i * i) % 60) as f32);
+            contree.insert(p, if i % 4 == 0 { 2 } else { 1 }).unwrap();
+        }
+        let (min, max) = (IVec3::new(-60, -20, 0), IVec3::new(30, 40, 40));
+
+        let expected: Vec<_> = contree
+            .query_aabb(min, max)
+            .filter(|&(_, material)| material == 2)
+            .collect();
+        assert!(!expected.is_empty());
+        assert_eq!(
+            contree.lights_in_aabb(min, max).collect::<Vec<_>>(),
+            expected
+        );
+    }
+
+    #[test]
+    fn changing_emission_relights() {
+        let mut contree = lit_contree();
+        contree
+            .fill_aabb(IVec3::splat(-3), IVec3::splat(3), 1)
+            .unwrap();
+        assert_eq!(contree.lights_in_aabb(IVec3::MIN, IVec3::MAX).count(), 0);
+
+        contree
+            .set_material(1, Material::new([0.5; 4], 0.).with_emission(1.))
+            .unwrap();
+        assert_lights_consistent(&contree);
+        assert_eq!(
+            contree.lights_in_aabb(IVec3::MIN, IVec3::MAX).count(),
+            7 * 7 * 7
+        );
+
+        let mut palette = contree.palette.clone();
+        palette.set(1, Material::new([0.5; 4], 0.)).unwrap();
+        contree.set_palette(palette);
+        assert_lights_consistent(&contree);
+        assert_eq!(contree.lights_in_aabb(IVec3::MIN, IVec3::MAX).count(), 0);
+    }
+}
@@ -0,0 +1,155 @@
+use super::{AIR, Addr, Contree, ContreeLeaf, node_management::Child, palette::MAX_MATERIALS};
+
+/// Most common material, ties going to the lowest index, air if there are none
+pub(crate) fn most_common(materials: impl IntoIterator<Item = u8>) -> u8 {
+    let mut counts = [0_u8; MAX_MATERIALS];
+    let mut best = (0, AIR);
+    for material in materials {
+        let count = &mut counts[material as usize];
+        *count += 1;
+        if (*count, std::cmp::Reverse(material)) > (best.0, std::cmp::Reverse(best.1)) {
+            best = (*count, material);
+        }
+    }
+    best.1
+}
+
+impl ContreeLeaf {
+    /// Most common material among the voxels
+    pub(crate) fn representative(&self) -> u8 {
+        most_common(
+            (0..64)
+                .filter(|&i| (self.contains >> i) & 1 == 1)
+                .map(|i| self.children[i]),
+        )
+    }
+}
+
+impl Contree<'_> {
+    /// Material standing in for a child when it is not traversed any further
+    pub(super) fn representative(&self, child: Child) -> u8 {
+        match child {
+            Child::Empty => AIR,
+            Child::Solid(material) => material,
+            Child::Inner(addr) => self.inners[addr as usize].material as u8,
+            Child::Leaf(addr) => self.leaves[addr as usize].representative(),
+        }
+    }
+
+    /// Recompute the material of an inner node from its children, without uploading it
+    ///
+    /// Each child counts once regardless of how many voxels it holds, which keeps this local
+    /// to the node at the cost of favouring sparse children.
+    pub(super) fn update_material(&mut self, addr: Addr) {
+        let node = self.inners[addr as usize];
+        let material = most_common(
+            (0..64)
+                .map(|i| self.representative(node.child(i)))
+                .filter(|&m| m != AIR),
+        );
+        self.inners[addr as usize].material = material as u32;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::{IVec3, Vec3};
+
+    use super::*;
+    use crate::test_util::create_contree;
+
+    /// Check that every inner node's material matches its children
+    fn assert_materials_consistent(contree: &Contree) {
+        fn check(contree: &Contree, addr: Addr) {
+            let node = contree.inners[addr as usize];
+            let expected = most_common((0..64).filter_map(|i| match node.child(i) {
+                Child::Empty => None,
+                Child::Inner(child) => {
+                    check(contree, child);
+                    Some(contree.inners[child as usize].material as u8)
+                }
+                child => Some(contree.representative(child)),
+            }));
+            assert_eq!(node.material, expected as u32, "node {addr}");
+        }
+        if let Some(root) = contree.root {
+            check(contree, root);
+        }
+    }
+
+    #[test]
+    fn most_common_ties_to_lowest() {
+        assert_eq!(most_common([]), AIR);
+        assert_eq!(most_common([4, 2, 4, 2, 7]), 2);
+        assert_eq!(most_common([9, 3, 9]), 9);
+    }
+
+    #[test]
+    fn insert_updates_materials() {
+        let mut contree = create_contree(64, Vec3::splat(-20.));
+        for i in 0..8 {
+            contree.insert(Vec3::new(i as f32, 0., 0.), 3).unwrap();
+            contree.insert(Vec3::new(i as f32, 20., 0.), 3).unwrap();
+        }
+        contree.insert(Vec3::new(0., 1., 0.), 5).unwrap();
+        assert_materials_consistent(&contree);
+        assert_eq!(contree.inners[contree.root.unwrap() as usize].material, 3);
+
+        contree.insert(Vec3::new(0., 20., 0.), 5).unwrap();
+        assert_materials_consistent(&contree);
+    }
+
+    #[test]
+    fn edits_keep_materials_consistent() {
+        let mut contree = create_contree(256, Vec3::splat(100.));
+        contree
+            .fill_aabb(IVec3::new(-40, -3, -40), IVec3::new(40, -1, 40), 2)
+            .unwrap();
+        contree
+            .fill_aabb(IVec3::new(-3, 0, -3), IVec3::new(3, 9, 3), 6)
+            .unwrap();
+        assert_materials_consistent(&contree);
+        assert_eq!(contree.inners[contree.root.unwrap() as usize].material, 2);
+
+        contree
+            .insert_many((0..60).map(|i| (Vec3::new(i as f32, 50., -i as f32), 8)))
+            .unwrap();
+        assert_materials_consistent(&contree);
+
+        contree
+            .fill_aabb(IVec3::new(-40, -3, -40), IVec3::new(40, -1, 40), AIR)
+            .unwrap();
+        for i in 0..30 {
+            contree.remove(Vec3::new(i as f32, 50., -i as f32)).unwrap();
+        }
+        assert_materials_consistent(&contree);
+    }
+
+    #[test]
+    fn find_at_depth_returns_material() {
+        let mut contree = create_contree(64, Vec3::splat(-20.));
+        contree
+            .fill_aabb(IVec3::new(0, 0, 0), IVec3::new(3, 3, 2), 4)
+            .unwrap();
+        contree.insert(Vec3::new(1., 1., 3.), 9).unwrap();
+        let p = Vec3::new(1., 1., 3.);
+
+        let far = contree.find_at_depth(p, 0).unwrap();
+        assert_eq!(far.depth, 0);
+        assert_eq!(far.material, Some(4));
+        assert_eq!(far.parent_address, contree.root.unwrap());
+
+        let leaf = contree.find_at_depth(p, 2).unwrap();
+        assert_eq!(leaf.depth, 2);
+        assert_eq!(leaf.material, Some(4));
+        assert!(leaf.leaf_address.is_some());
+
+        assert_eq!(contree.find_at_depth(p, 3).unwrap().material, Some(9));
+        assert_eq!(contree.find_at_depth(p, u8::MAX).unwrap().material, Some(9));
+
+        // empty space is empty at every depth
+        let empty = Vec3::new(20., 20., 20.);
+        assert_eq!(contree.find_at_depth(empty, 0).unwrap().material, Some(4));
+        assert_eq!(contree.find_at_depth(empty, 1).unwrap().material, None);
+    }
+}
@@ -1,46 +1,106 @@
-use glam::Vec3;
+use glam::{UVec3, Vec3};
 
-use super::{Addr, ChildIndex, Contree, finding::FindResult, util::*};
+use super::{
+    Addr, ChildIndex, Contree, ContreeError, finding::FindResult, node_management::Child, util::*,
+};
 
 impl Contree<'_> {
     /// Grow upward until the position is in bounds
-    fn grow_to_accomodate(&mut self, pos: Vec3) {
-        // ensures there is a root
-        if self.root.is_none() {
-            self.root = Some(self.create_root_node());
-            self.center_offset = pos;
+    ///
+    /// Fails without growing past [`Contree::max_size`] or to positions that are not
+    /// [addressable](is_addressable)
+    pub(super) fn grow_to_accomodate(&mut self, pos: Vec3) -> Result<(), ContreeError> {
+        if !is_addressable(pos) {
+            return Err(ContreeError::OutOfRange(pos));
         }
+        Self::check_size(self.size)?;
+
+        let mut root = match self.root {
+            Some(_) => self.checked_root()?,
+            None => {
+                let root = self.create_root_node()?;
+                self.root = Some(root);
+                self.center_offset = pos.as_dvec3().round();
+                self.write_bounds();
+                root
+            }
+        };
 
         while !self.in_bounds(pos) {
-            let new_root = self.create_root_node();
-
-            // TODO: Find a better way to grow
-            let new_center = ((pos - self.center_offset) / self.size as f32)
-                .round()
-                .clamp(-Vec3::splat(3.), Vec3::splat(3.)) // clamp insures the current tree is enclosed
-                * self.size as f32;
-            let old_root_coords = self.center_offset;
-            self.size *= 4;
-            self.center_offset = new_center;
-
-            // let mut next_morton_index = MAX_MORTON_INDEX + 1 - (self.size.ilog(4) as u8);
-            let old_root_new_index = morton_index(
-                morton_code(self.normalize(old_root_coords)),
-                MAX_MORTON_INDEX,
-            )
-            .unwrap();
-
-            // set current node as child of new node
-            self.inners[new_root as usize].children[old_root_new_index as usize] =
-                self.root.unwrap();
+            // nothing needs to be kept, so the tree can move instead, staying on the grid of
+            // integer voxel centers so positions map to the same voxels whatever came before
+            if self.inners[root as usize].contains == 0 {
+                self.center_offset = pos.as_dvec3().round();
+                self.write_bounds();
+                continue;
+            }
+            if self.size >= self.max_size() {
+                return Err(ContreeError::OutOfRange(pos));
+            }
+
+            // the old root becomes one of the two middle children on each axis, the one
+            // leaving more room on the side of the position
+            let slot = UVec3::select(
+                pos.as_dvec3().cmpge(self.center_offset),
+                UVec3::ONE,
+                UVec3::splat(2),
+            );
+            let index = morton_index(morton_code(slot), MAX_MORTON_INDEX).unwrap();
+            let new_root = self.alloc_inner_node()?;
+            let child = self.simplify(Child::Inner(root));
+            let lit = self.is_lit(child);
+            let node = &mut self.inners[new_root as usize];
+            node.set_child(index, child);
+            node.set_light(index, lit);
+            self.update_material(new_root);
             self.binding
                 .write_inner(new_root, &[self.inners[new_root as usize]]);
 
+            self.center_offset += (1.5 - slot.as_dvec3()) * self.size as f64;
+            self.size *= 4;
             self.root = Some(new_root);
+            self.write_bounds();
+            root = new_root;
         }
+        Ok(())
     }
-    pub fn insert(&mut self, pos: Vec3, material: u8) -> Option<FindResult> {
-        self.grow_to_accomodate(pos);
+
+    /// Grow upward until both corners of a box are in bounds
+    ///
+    /// An empty tree moves to the middle of the box and grows around it, since growing towards
+    /// one corner would move it away from the other.
+    pub(super) fn grow_to_cover(&mut self, min: Vec3, max: Vec3) -> Result<(), ContreeError> {
+        if let Some(p) = [min, max].into_iter().find(|&p| !is_addressable(p)) {
+            return Err(ContreeError::OutOfRange(p));
+        }
+        let empty = match self.root {
+            Some(_) => self.inners[self.checked_root()? as usize].contains == 0,
+            None => true,
+        };
+        if empty && !(self.in_bounds(min) && self.in_bounds(max)) {
+            self.grow_to_accomodate((min + max) / 2.)?;
+            while let Some(p) = [min, max].into_iter().find(|&p| !self.in_bounds(p)) {
+                if self.size >= self.max_size() {
+                    return Err(ContreeError::OutOfRange(p));
+                }
+                self.size *= 4;
+            }
+            self.write_bounds();
+        }
+        self.grow_to_accomodate(min)?;
+        self.grow_to_accomodate(max)
+    }
+
+    /// Set the voxel at a position, growing the tree if it is out of bounds
+    ///
+    /// Fails if the position is past the largest tree or the tree is corrupted
+    pub fn insert(&mut self, pos: Vec3, material: u8) -> Result<FindResult, ContreeError> {
+        self.grow_to_accomodate(pos)?;
+        if !self.expand_path(self.locate(pos)?, material)? {
+            // already inside a solid node of the same material
+            return self.find(pos);
+        }
+        self.record(pos, material)?;
 
         let FindResult {
             leaf_address,
@@ -50,37 +110,36 @@ impl Contree<'_> {
         } = self.find(pos)?;
 
         let (code, mut next_morton_index) = traversal_iter;
+        let light = self.palette.is_emissive(material) as u64;
         match leaf_address {
             Some(leaf_addr) => {
-                let leaf = self
-                    .leaves
-                    .get_mut(leaf_addr as usize)
-                    .expect("Leaf node does not exist!");
-
                 let child_index = morton_index(code, next_morton_index)
-                    .expect("Traversal iter should not be empty!");
+                    .ok_or(ContreeError::InvalidSize(self.size))?;
                 next_morton_index += 1;
+                let leaf = &mut self.leaves[leaf_addr as usize];
 
                 leaf.children[child_index as usize] = material;
                 leaf.contains |= 1 << child_index;
+                leaf.light = (leaf.light & !(1 << child_index)) | (light << child_index);
                 self.binding.write_leaf(leaf_addr, &[*leaf]);
             }
             None => {
                 let (leaf_addr, child_index) =
-                    self.add_parents(traversal_iter, &mut parent_address);
+                    self.add_parents(traversal_iter, &mut parent_address)?;
                 next_morton_index = MAX_MORTON_INDEX + 1;
 
-                let leaf = self
-                    .leaves
-                    .get_mut(leaf_addr as usize)
-                    .expect("Leaf node does not exist!");
+                let leaf = &mut self.leaves[leaf_addr as usize];
 
                 leaf.children[child_index as usize] = material;
                 leaf.contains |= 1 << child_index;
+                leaf.light |= light << child_index;
                 self.binding.write_leaf(leaf_addr, &[*leaf]);
             }
         }
-        Some(FindResult {
+        self.collapse_path(code);
+        self.update_path(code);
+        self.check_invariants();
+        Ok(FindResult {
             material: Some(material),
             leaf_address,
             parent_address,
@@ -89,41 +148,104 @@ impl Contree<'_> {
         })
     }
 
+    /// Split every solid node on the path to a voxel, so the path ends in a leaf or empty child
+    ///
+    /// Returns false without changing anything if the path runs into a solid node of `keep`.
+    /// Fails before changing anything if a node on the path is corrupted, every node below a
+    /// split one is new.
+    pub(super) fn expand_path(&mut self, code: MortonCode, keep: u8) -> Result<bool, ContreeError> {
+        if self.root.is_none() {
+            return Ok(true);
+        }
+        let mut parent = self.checked_root()?;
+        for index in self.first_morton_index()..MAX_MORTON_INDEX {
+            self.check_links(parent)?;
+            let child_index = morton_index(code, index).unwrap();
+            let child = match self.inners[parent as usize].child(child_index) {
+                Child::Solid(material) if material == keep => return Ok(false),
+                Child::Solid(material) => {
+                    let child = self.expand_solid(material, index == MAX_MORTON_INDEX - 1)?;
+                    self.inners[parent as usize].set_child(child_index, child);
+                    match child {
+                        Child::Inner(addr) => self
+                            .binding
+                            .write_inner(addr, &[self.inners[addr as usize]]),
+                        Child::Leaf(addr) => {
+                            self.binding.write_leaf(addr, &[self.leaves[addr as usize]])
+                        }
+                        _ => unreachable!(),
+                    }
+                    self.binding
+                        .write_inner(parent, &[self.inners[parent as usize]]);
+                    child
+                }
+                child => child,
+            };
+            match child {
+                Child::Inner(addr) => parent = addr,
+                _ => break,
+            }
+        }
+        Ok(true)
+    }
+
+    /// Turn nodes on the path to a voxel that are filled with one material into solid children
+    fn collapse_path(&mut self, code: MortonCode) {
+        let Some(mut addr) = self.root else {
+            return;
+        };
+
+        let mut path = Vec::new();
+        for index in self.first_morton_index()..MAX_MORTON_INDEX {
+            let child_index = morton_index(code, index).unwrap();
+            path.push((addr, child_index));
+            match self.inners[addr as usize].child(child_index) {
+                Child::Inner(child) => addr = child,
+                _ => break,
+            }
+        }
+
+        while let Some((parent, child_index)) = path.pop() {
+            let child = self.inners[parent as usize].child(child_index);
+            let simplified = self.simplify(child);
+            if simplified == child {
+                break;
+            }
+            self.inners[parent as usize].set_child(child_index, simplified);
+            self.binding
+                .write_inner(parent, &[self.inners[parent as usize]]);
+        }
+    }
+
+    /// Create the missing nodes down to a voxel, returning its leaf and index within it
     fn add_parents(
         &mut self,
-        traversal_iter: (u64, u8),
+        traversal_iter: (MortonCode, u8),
         parent_address: &mut Addr,
-    ) -> (Addr, ChildIndex) {
+    ) -> Result<(Addr, ChildIndex), ContreeError> {
+        let (code, first) = traversal_iter;
         let mut leaf_addr = 0;
-        for i in traversal_iter.1..=MAX_MORTON_INDEX {
+        for i in first..MAX_MORTON_INDEX {
             let parent: Addr = *parent_address;
-            let child_index = morton_index(traversal_iter.0, i).unwrap();
-            match i {
-                MAX_MORTON_INDEX => return (leaf_addr, child_index),
-                x if x == MAX_MORTON_INDEX - 1 => {
-                    leaf_addr = self.create_leaf_node(parent, child_index)
-                }
-                _ => *parent_address = self.create_inner_node(parent, child_index),
+            let child_index = morton_index(code, i).unwrap();
+            if i == MAX_MORTON_INDEX - 1 {
+                leaf_addr = self.create_leaf_node(parent, child_index)?;
+            } else {
+                *parent_address = self.create_inner_node(parent, child_index)?;
             }
         }
-        unreachable!("Never reached bottom of traversal stack!")
+        Ok((leaf_addr, morton_index(code, MAX_MORTON_INDEX).unwrap()))
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use glam::{DVec3, IVec3};
+
     use super::*;
     use crate::ContreeInner;
+    use crate::test_util::{corrupt_root_link, create_contree};
 
-    fn create_contree(size: u32, p: Vec3) -> Contree<'static> {
-        assert!(size > 4, "The root node cannot be a leaf!");
-        let mut contree = Contree {
-            size,
-            ..Default::default()
-        };
-        contree.insert(p, 10);
-        contree
-    }
     #[test]
     fn insert_many_no_grow() {
         let p = Vec3::new(0., 0., 0.);
@@ -133,18 +255,22 @@ mod tests {
             inners: vec![ContreeInner {
                 contains: 0,
                 leaf: 0,
+                solid: 0,
                 light: 0,
+                material: 0,
+                padding: 0,
                 children: [0; 64],
-            }],
-            leaves: Vec::new(),
+            }]
+            .into(),
+            leaves: Default::default(),
             ..Default::default()
         };
-        contree.insert(p, 10);
-        contree.insert(Vec3::new(0., 0., 1.), 1);
-        contree.insert(Vec3::new(1., 0., 0.), 3);
-        contree.insert(Vec3::new(-10., 10., 10.), 4);
-        contree.insert(Vec3::new(-10., 0., 0.), 5);
-        contree.insert(Vec3::new(-10., -10., 0.), 6);
+        contree.insert(p, 10).unwrap();
+        contree.insert(Vec3::new(0., 0., 1.), 1).unwrap();
+        contree.insert(Vec3::new(1., 0., 0.), 3).unwrap();
+        contree.insert(Vec3::new(-10., 10., 10.), 4).unwrap();
+        contree.insert(Vec3::new(-10., 0., 0.), 5).unwrap();
+        contree.insert(Vec3::new(-10., -10., 0.), 6).unwrap();
 
         assert_eq!(contree.root, Some(0));
         assert_eq!(contree.size, 4_u32.pow(3));
@@ -186,9 +312,9 @@ mod tests {
     fn grow_positive() {
         let mut contree = create_contree(16, Vec3::ZERO);
 
-        contree.insert(Vec3::splat(8.), 10);
+        contree.insert(Vec3::splat(8.), 10).unwrap();
         assert_eq!(contree.size, 64);
-        assert_eq!(contree.center_offset, Vec3::splat(16.));
+        assert_eq!(contree.center_offset, DVec3::splat(8.));
 
         assert!(contree.in_bounds(Vec3::splat(32.)));
         assert!(contree.in_bounds(Vec3::splat(-16.)));
@@ -199,9 +325,9 @@ mod tests {
     fn grow_negative() {
         let mut contree = create_contree(16, Vec3::ZERO);
 
-        contree.insert(Vec3::splat(-9.), 10);
+        contree.insert(Vec3::splat(-9.), 10).unwrap();
         assert_eq!(contree.size, 64);
-        assert_eq!(contree.center_offset, Vec3::splat(-16.));
+        assert_eq!(contree.center_offset, DVec3::splat(-8.));
 
         assert!(contree.in_bounds(Vec3::splat(-32.)));
         assert!(contree.in_bounds(Vec3::splat(15.)));
@@ -212,10 +338,222 @@ mod tests {
     fn grow_multiple_times() {
         let mut contree = create_contree(16, Vec3::ZERO);
 
-        contree.insert(Vec3::splat(100.), 10);
+        contree.insert(Vec3::splat(100.), 10).unwrap();
         assert_eq!(contree.size, 256);
-        assert_eq!(contree.center_offset, Vec3::splat(64.));
+        assert_eq!(contree.center_offset, DVec3::splat(40.));
 
         assert!(contree.in_bounds(Vec3::splat(-8.)));
     }
+
+    #[test]
+    fn grow_keeps_voxels() {
+        for x in [-1., 1.] {
+            for y in [-1., 1.] {
+                for z in [-1., 1.] {
+                    let dir = Vec3::new(x, y, z);
+                    let mut contree = create_contree(16, Vec3::ZERO);
+                    let mut inserted = vec![(Vec3::ZERO, 10)];
+                    for (i, dist) in [10., 50., 300., 2000.].into_iter().enumerate() {
+                        let p = dir * dist;
+                        contree.insert(p, i as u8 + 1).unwrap();
+                        inserted.push((p, i as u8 + 1));
+
+                        let root = contree.inners[contree.root.unwrap() as usize];
+                        assert_eq!(root.contains.count_ones(), 2, "growing along {dir}");
+                        for &(p, material) in &inserted {
+                            assert_eq!(
+                                contree.find(p).unwrap().material,
+                                Some(material),
+                                "{p} after growing along {dir}"
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn grow_empty_recenters() {
+        let mut contree = Contree::default();
+        contree.insert(Vec3::ZERO, 1).unwrap();
+        contree.remove(Vec3::ZERO).unwrap();
+
+        let far = Vec3::new(500., -500., 20.);
+        contree.insert(far, 2).unwrap();
+        assert_eq!(contree.size, 16);
+        assert_eq!(contree.center_offset, far.as_dvec3());
+        assert_eq!(contree.find(far).unwrap().material, Some(2));
+    }
+
+    #[test]
+    fn recentering_keeps_voxel_grid() {
+        let p = Vec3::new(100.7, 0., 0.);
+        let fresh = {
+            let mut contree = Contree::default();
+            contree.insert(p, 2).unwrap();
+            contree
+        };
+        let grown = {
+            let mut contree = create_contree(16, Vec3::ZERO);
+            contree.insert(p, 2).unwrap();
+            contree
+        };
+        let moved = {
+            let mut contree = create_contree(16, Vec3::ZERO);
+            contree.remove(Vec3::ZERO).unwrap();
+            contree.insert(p, 2).unwrap();
+            contree
+        };
+
+        // voxel 101 covers 100.5..101.5 in every tree
+        for contree in [fresh, grown, moved] {
+            assert_eq!(contree.center_offset.fract(), DVec3::ZERO);
+            assert_eq!(
+                contree.find(Vec3::new(101.2, 0., 0.)).unwrap().material,
+                Some(2)
+            );
+            assert_eq!(
+                contree.find(Vec3::new(100.2, 0., 0.)).unwrap().material,
+                None
+            );
+            assert!(
+                contree
+                    .iter()
+                    .any(|voxel| voxel == (IVec3::new(101, 0, 0), 2))
+            );
+        }
+    }
+
+    #[test]
+    fn grow_to_max_size_keeps_neighbours() {
+        let mut contree = create_contree(16, Vec3::ZERO);
+        contree.insert(Vec3::X, 3).unwrap();
+        // growing towards a far voxel shifts the center by odd multiples of half the size
+        let far = Vec3::splat(-(1 << 23) as f32 - 4.);
+        contree.insert(far, 4).unwrap();
+        contree.insert(far + Vec3::X, 5).unwrap();
+        assert_eq!(contree.size, MAX_SIZE);
+
+        assert_eq!(contree.find(Vec3::ZERO).unwrap().material, Some(10));
+        assert_eq!(contree.find(Vec3::X).unwrap().material, Some(3));
+        assert_eq!(contree.find(far).unwrap().material, Some(4));
+        assert_eq!(contree.find(far + Vec3::X).unwrap().material, Some(5));
+        assert_eq!(contree.iter().count(), 4);
+        assert!(contree.validate().is_ok());
+    }
+
+    #[test]
+    fn insert_collapses_full_leaf() {
+        let mut contree = create_contree(64, Vec3::splat(-20.));
+        // normalized 32..36 on every axis is exactly one leaf
+        for x in 0..4 {
+            for y in 0..4 {
+                for z in 0..4 {
+                    contree
+                        .insert(Vec3::new(x as f32, y as f32, z as f32), 7)
+                        .unwrap();
+                }
+            }
+        }
+
+        let found = contree.find(Vec3::ONE).unwrap();
+        assert_eq!(found.material, Some(7));
+        assert!(found.leaf_address.is_none());
+        assert_eq!(contree.leaves.len() - contree.leaf_tombstones.len(), 1);
+
+        // a matching insert leaves the solid node alone, a different one splits it
+        contree.insert(Vec3::ONE, 7).unwrap();
+        assert!(contree.find(Vec3::ONE).unwrap().leaf_address.is_none());
+        contree.insert(Vec3::ONE, 2).unwrap();
+        assert_eq!(contree.find(Vec3::ONE).unwrap().material, Some(2));
+        assert_eq!(contree.find(Vec3::ZERO).unwrap().material, Some(7));
+        assert!(contree.find(Vec3::ZERO).unwrap().leaf_address.is_some());
+    }
+
+    #[test]
+    fn insert_out_of_range() {
+        let mut contree = create_contree(64, Vec3::ZERO);
+
+        assert!(matches!(
+            contree.insert(Vec3::new(0., f32::NAN, 0.), 1),
+            Err(ContreeError::OutOfRange(_))
+        ));
+        let far = Vec3::new(0., 0., MAX_COORDINATE);
+        assert_eq!(
+            contree.insert(far, 1).err(),
+            Some(ContreeError::OutOfRange(far))
+        );
+
+        // growth stops at the largest tree
+        assert_eq!(contree.size, MAX_SIZE);
+    }
+
+    #[test]
+    fn positions_past_f32_precision() {
+        let mut contree = Contree::default();
+        // past 2^24 an f32 only holds every other whole number
+        let far = Vec3::new(MAX_COORDINATE + 2., 0., 0.);
+
+        assert_eq!(
+            contree.insert(far, 1).err(),
+            Some(ContreeError::OutOfRange(far))
+        );
+        assert_eq!(contree.size, 16);
+        assert_eq!(contree.find(far).err(), Some(ContreeError::OutOfRange(far)));
+        assert_eq!(contree.remove(far), Ok(None));
+        assert!(matches!(
+            contree.insert_many([(Vec3::ZERO, 1), (far, 2)]),
+            Err(ContreeError::OutOfRange(_))
+        ));
+        assert!(contree.iter().next().is_none());
+    }
+
+    #[test]
+    fn insert_millions() {
+        let mut contree = create_contree(64, Vec3::ZERO);
+        let far = Vec3::new(3_000_000., -2_000_000., 1_500_000.);
+
+        contree.insert(far, 3).unwrap();
+        assert_eq!(contree.find(far).unwrap().material, Some(3));
+        assert_eq!(contree.find(Vec3::ZERO).unwrap().material, Some(10));
+        assert_eq!(
+            contree
+                .raycast(far + Vec3::Y * 100., Vec3::NEG_Y)
+                .unwrap()
+                .map(|hit| hit.voxel),
+            Some(far.as_ivec3())
+        );
+    }
+
+    #[test]
+    fn insert_past_max_depth() {
+        let mut contree = create_contree(64, Vec3::ZERO);
+        contree.max_depth = 4;
+
+        contree.insert(Vec3::splat(100.), 2).unwrap();
+        assert_eq!(contree.size, 256);
+        let far = Vec3::splat(1000.);
+        assert_eq!(
+            contree.insert(far, 2).err(),
+            Some(ContreeError::OutOfRange(far))
+        );
+        assert_eq!(contree.size, 256);
+    }
+
+    #[test]
+    fn insert_reports_corrupted_tree() {
+        let mut contree = create_contree(64, Vec3::ZERO);
+        let corrupted = corrupt_root_link(&mut contree);
+
+        contree.begin_transaction();
+        assert_eq!(contree.insert(Vec3::ONE, 3).err(), Some(corrupted));
+        assert_eq!(contree.commit_transaction(), 0);
+
+        contree.root = Some(1000);
+        assert_eq!(
+            contree.insert(Vec3::ONE, 3).err(),
+            Some(ContreeError::NoRoot)
+        );
+    }
 }
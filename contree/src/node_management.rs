@@ -1,64 +1,370 @@
-use super::{Addr, ChildIndex, Contree, ContreeInner, ContreeLeaf};
+use std::collections::BTreeSet;
+
+use bytemuck::Zeroable;
+
+use super::{Addr, ChildIndex, Contree, ContreeError, ContreeInner, ContreeLeaf, util::*};
 
 bitflags::bitflags! {
     pub struct TreeFlags: u8 {
         const EXISTS = 1 << 0;
         const LEAF = 1 << 1;
         const LIGHT = 1 << 2;
+        const SOLID = 1 << 3;
         const _ = 0; // set all other bits to zero
     }
 }
 
+/// What a single child slot of an inner node refers to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Child {
+    Empty,
+    Inner(Addr),
+    Leaf(Addr),
+    /// Completely filled with a single material
+    Solid(u8),
+}
+
+impl ContreeInner {
+    pub(crate) fn child(&self, index: ChildIndex) -> Child {
+        let exists = (self.contains >> index) & 1 == 1;
+        let leaf = (self.leaf >> index) & 1 == 1;
+        let solid = (self.solid >> index) & 1 == 1;
+        match (exists, leaf, solid) {
+            (false, _, _) => Child::Empty,
+            (true, _, true) => Child::Solid(self.children[index as usize] as u8),
+            (true, false, false) => Child::Inner(self.children[index as usize]),
+            (true, true, false) => Child::Leaf(self.children[index as usize]),
+        }
+    }
+
+    /// Point a child slot at a node, updating the bitmasks to match
+    ///
+    /// Does not touch the light mask
+    pub(crate) fn set_child(&mut self, index: ChildIndex, child: Child) {
+        let bit = 1 << index;
+        let (contains, leaf, solid, addr) = match child {
+            Child::Empty => (false, false, false, 0),
+            Child::Inner(addr) => (true, false, false, addr),
+            Child::Leaf(addr) => (true, true, false, addr),
+            Child::Solid(material) => (true, false, true, material as Addr),
+        };
+        self.contains = (self.contains & !bit) | ((contains as u64) << index);
+        self.leaf = (self.leaf & !bit) | ((leaf as u64) << index);
+        self.solid = (self.solid & !bit) | ((solid as u64) << index);
+        self.children[index as usize] = addr;
+    }
+
+    /// Material filling the whole node, if every child is solid with the same one
+    pub(crate) fn uniform(&self) -> Option<u8> {
+        let material = self.children[0];
+        (self.solid == u64::MAX && self.children.iter().all(|&m| m == material))
+            .then_some(material as u8)
+    }
+
+    /// Mark whether a child has any light sources below it
+    pub(crate) fn set_light(&mut self, index: ChildIndex, lit: bool) {
+        self.light = (self.light & !(1 << index)) | ((lit as u64) << index);
+    }
+}
+
+impl ContreeLeaf {
+    /// Material filling the whole leaf, if every voxel is present and the same
+    pub(crate) fn uniform(&self) -> Option<u8> {
+        let material = self.children[0];
+        (self.contains == u64::MAX && self.children.iter().all(|&m| m == material))
+            .then_some(material)
+    }
+}
+
 impl Contree<'_> {
-    pub(super) fn create_root_node(&mut self) -> Addr {
+    /// Allocate an empty inner node without uploading it
+    pub(super) fn alloc_inner_node(&mut self) -> Result<Addr, ContreeError> {
         let new_node = ContreeInner {
             contains: 0,
             leaf: 0,
+            solid: 0,
             light: 0,
+            material: 0,
+            padding: 0,
             children: [0; 64],
         };
-        let addr = match self.inner_tombstones.pop() {
+        match self.inner_tombstones.pop() {
             Some(addr) => {
                 self.inners[addr as usize] = new_node;
-                addr
+                Ok(addr)
             }
             None => {
+                let addr =
+                    Addr::try_from(self.inners.len()).map_err(|_| ContreeError::ArenaFull)?;
                 self.inners.push(new_node);
-                (self.inners.len() - 1) as Addr
+                self.binding
+                    .arenas_grown(self.inners.len(), self.leaves.len());
+                Ok(addr)
             }
-        };
-        self.binding.write_inner(addr, &[new_node]);
-        addr
+        }
     }
 
-    pub(super) fn create_inner_node(&mut self, parent: Addr, index: ChildIndex) -> Addr {
-        let addr = self.create_root_node();
-        self.inners[parent as usize].children[index as usize] = addr;
-        self.update_parent_bitflags(parent, index, TreeFlags::EXISTS);
-        addr
-    }
-
-    pub(super) fn create_leaf_node(&mut self, parent: Addr, index: ChildIndex) -> Addr {
+    /// Allocate an empty leaf node without uploading it
+    pub(super) fn alloc_leaf_node(&mut self) -> Result<Addr, ContreeError> {
         let new_node = ContreeLeaf {
             contains: 0,
             light: 0,
             children: [0; 64],
         };
-        let addr = match self.leaf_tombstones.pop() {
+        match self.leaf_tombstones.pop() {
             Some(addr) => {
                 self.leaves[addr as usize] = new_node;
-                addr
+                Ok(addr)
             }
             None => {
+                let addr =
+                    Addr::try_from(self.leaves.len()).map_err(|_| ContreeError::ArenaFull)?;
                 self.leaves.push(new_node);
-                (self.leaves.len() - 1) as Addr
+                self.binding
+                    .arenas_grown(self.inners.len(), self.leaves.len());
+                Ok(addr)
             }
-        };
+        }
+    }
+
+    pub(super) fn create_root_node(&mut self) -> Result<Addr, ContreeError> {
+        let addr = self.alloc_inner_node()?;
+        self.binding
+            .write_inner(addr, &[self.inners[addr as usize]]);
+        Ok(addr)
+    }
+
+    pub(super) fn create_inner_node(
+        &mut self,
+        parent: Addr,
+        index: ChildIndex,
+    ) -> Result<Addr, ContreeError> {
+        let addr = self.create_root_node()?;
+        self.inners[parent as usize].children[index as usize] = addr;
+        self.update_parent_bitflags(parent, index, TreeFlags::EXISTS);
+        Ok(addr)
+    }
+
+    pub(super) fn create_leaf_node(
+        &mut self,
+        parent: Addr,
+        index: ChildIndex,
+    ) -> Result<Addr, ContreeError> {
+        let addr = self.alloc_leaf_node()?;
         self.inners[parent as usize].children[index as usize] = addr;
         self.update_parent_bitflags(parent, index, TreeFlags::EXISTS | TreeFlags::LEAF);
 
-        self.binding.write_leaf(addr, &[new_node]);
-        addr
+        self.binding.write_leaf(addr, &[self.leaves[addr as usize]]);
+        Ok(addr)
+    }
+
+    /// Root of the tree, failing if it does not point at an inner node
+    pub(super) fn checked_root(&self) -> Result<Addr, ContreeError> {
+        self.root
+            .filter(|&root| (root as usize) < self.inners.len())
+            .ok_or(ContreeError::NoRoot)
+    }
+
+    /// Check that every child of an inner node points into its arena
+    ///
+    /// Edits read all children of the nodes they change to update materials and light,
+    /// so a node is checked before it is changed.
+    pub(super) fn check_links(&self, addr: Addr) -> Result<(), ContreeError> {
+        let node = &self.inners[addr as usize];
+        for index in 0..64 {
+            let valid = match node.child(index) {
+                Child::Inner(child) => (child as usize) < self.inners.len(),
+                Child::Leaf(child) => (child as usize) < self.leaves.len(),
+                Child::Empty | Child::Solid(_) => true,
+            };
+            if !valid {
+                return Err(ContreeError::CorruptedLink {
+                    parent: addr,
+                    index,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Check the links of a child and every node below it, before they are freed
+    pub(super) fn check_subtree(&self, child: Child) -> Result<(), ContreeError> {
+        let Child::Inner(addr) = child else {
+            return Ok(());
+        };
+        let mut stack = vec![addr];
+        while let Some(addr) = stack.pop() {
+            self.check_links(addr)?;
+            let node = &self.inners[addr as usize];
+            stack.extend((0..64).filter_map(|i| match node.child(i) {
+                Child::Inner(child) => Some(child),
+                _ => None,
+            }));
+        }
+        Ok(())
+    }
+
+    /// Return an inner node to the arena, its slot is reused by the next allocation
+    pub(super) fn free_inner_node(&mut self, addr: Addr) {
+        self.inners[addr as usize] = ContreeInner::zeroed();
+        self.inner_tombstones.push(addr);
+        self.binding.free_inner(addr);
+    }
+
+    /// Return a leaf node to the arena, its slot is reused by the next allocation
+    pub(super) fn free_leaf_node(&mut self, addr: Addr) {
+        self.leaves[addr as usize] = ContreeLeaf::zeroed();
+        self.leaf_tombstones.push(addr);
+        self.binding.free_leaf(addr);
+    }
+
+    /// Free an inner node along with every node below it
+    pub(super) fn free_subtree(&mut self, addr: Addr) {
+        let node = self.inners[addr as usize];
+        for i in 0..64 {
+            match node.child(i) {
+                Child::Empty => {}
+                Child::Inner(child) => self.free_subtree(child),
+                Child::Leaf(child) => self.free_leaf_node(child),
+                Child::Solid(_) => {}
+            }
+        }
+        self.free_inner_node(addr);
+    }
+
+    /// Free a child node along with every node below it
+    pub(super) fn free_child(&mut self, child: Child) {
+        match child {
+            Child::Inner(addr) => self.free_subtree(addr),
+            Child::Leaf(addr) => self.free_leaf_node(addr),
+            Child::Empty | Child::Solid(_) => {}
+        }
+    }
+
+    /// Split a solid child into a node holding the same material everywhere, without uploading it
+    ///
+    /// `leaf` is whether the child sits at the bottom of the tree
+    pub(super) fn expand_solid(&mut self, material: u8, leaf: bool) -> Result<Child, ContreeError> {
+        let light = if self.palette.is_emissive(material) {
+            u64::MAX
+        } else {
+            0
+        };
+        if leaf {
+            let addr = self.alloc_leaf_node()?;
+            self.leaves[addr as usize] = ContreeLeaf {
+                contains: u64::MAX,
+                light,
+                children: [material; 64],
+            };
+            Ok(Child::Leaf(addr))
+        } else {
+            let addr = self.alloc_inner_node()?;
+            self.inners[addr as usize] = ContreeInner {
+                contains: u64::MAX,
+                leaf: 0,
+                solid: u64::MAX,
+                light,
+                material: material as u32,
+                padding: 0,
+                children: [material as Addr; 64],
+            };
+            Ok(Child::Inner(addr))
+        }
+    }
+
+    /// Replace a child node by the simplest equivalent, freeing it if it changes
+    ///
+    /// Nodes left without children become empty and nodes filled with one material become solid.
+    pub(super) fn simplify(&mut self, child: Child) -> Child {
+        let simplified = match child {
+            Child::Inner(addr) => {
+                let node = &self.inners[addr as usize];
+                match node.uniform() {
+                    _ if node.contains == 0 => Child::Empty,
+                    Some(material) => Child::Solid(material),
+                    None => return child,
+                }
+            }
+            Child::Leaf(addr) => {
+                let leaf = &self.leaves[addr as usize];
+                match leaf.uniform() {
+                    _ if leaf.contains == 0 => Child::Empty,
+                    Some(material) => Child::Solid(material),
+                    None => return child,
+                }
+            }
+            _ => return child,
+        };
+        self.free_child(child);
+        simplified
+    }
+
+    /// Recompute the light bits and material of every inner node on the path to a voxel,
+    /// from the bottom up
+    ///
+    /// Only nodes that changed are uploaded
+    pub(super) fn update_path(&mut self, code: MortonCode) {
+        let Some(mut addr) = self.root else {
+            return;
+        };
+
+        let mut path = Vec::new();
+        for index in self.first_morton_index()..MAX_MORTON_INDEX {
+            let child_index = morton_index(code, index).unwrap();
+            path.push((addr, child_index));
+            match self.inners[addr as usize].child(child_index) {
+                Child::Inner(child) => addr = child,
+                _ => break,
+            }
+        }
+
+        while let Some((addr, child_index)) = path.pop() {
+            let lit = self.is_lit(self.inners[addr as usize].child(child_index));
+            let before = self.inners[addr as usize];
+            self.inners[addr as usize].set_light(child_index, lit);
+            self.update_material(addr);
+
+            let node = self.inners[addr as usize];
+            if node.light != before.light || node.material != before.material {
+                self.binding.write_inner(addr, &[node]);
+            }
+        }
+    }
+
+    /// Tell the binding where the root is and what space the tree covers
+    pub(super) fn write_bounds(&self) {
+        self.binding
+            .bounds_changed(self.root, self.size, self.center_offset);
+    }
+
+    /// Upload both arenas, the palette and the bounds in full, with one write per chunk of nodes
+    pub(super) fn write_all(&self) {
+        self.binding.resync();
+        self.binding
+            .arenas_grown(self.inners.len(), self.leaves.len());
+        if !self.inners.is_empty() {
+            self.binding
+                .write_inner(0, &self.inners.contiguous(0..self.inners.len()));
+        }
+        if !self.leaves.is_empty() {
+            self.binding
+                .write_leaf(0, &self.leaves.contiguous(0..self.leaves.len()));
+        }
+        self.write_palette();
+        self.write_bounds();
+    }
+
+    /// Upload a set of nodes using one write per run of consecutive addresses
+    pub(super) fn write_ranges(&self, inners: &BTreeSet<Addr>, leaves: &BTreeSet<Addr>) {
+        for (start, end) in contiguous_ranges(inners) {
+            let data = self.inners.contiguous(start as usize..end as usize);
+            self.binding.write_inner(start, &data);
+        }
+        for (start, end) in contiguous_ranges(leaves) {
+            let data = self.leaves.contiguous(start as usize..end as usize);
+            self.binding.write_leaf(start, &data);
+        }
     }
 
     fn update_parent_bitflags(&mut self, parent: Addr, child: ChildIndex, flags: TreeFlags) {
@@ -66,7 +372,36 @@ impl Contree<'_> {
         parent_node.contains |= (flags.contains(TreeFlags::EXISTS) as u64) << child;
         parent_node.leaf |= (flags.contains(TreeFlags::LEAF) as u64) << child;
         parent_node.light |= (flags.contains(TreeFlags::LIGHT) as u64) << child;
+        parent_node.solid |= (flags.contains(TreeFlags::SOLID) as u64) << child;
 
         self.binding.write_inner(parent, &[*parent_node]);
     }
+
+    pub(super) fn clear_parent_bitflags(
+        &mut self,
+        parent: Addr,
+        child: ChildIndex,
+        flags: TreeFlags,
+    ) {
+        let parent_node = &mut self.inners[parent as usize];
+        parent_node.contains &= !((flags.contains(TreeFlags::EXISTS) as u64) << child);
+        parent_node.leaf &= !((flags.contains(TreeFlags::LEAF) as u64) << child);
+        parent_node.light &= !((flags.contains(TreeFlags::LIGHT) as u64) << child);
+        parent_node.solid &= !((flags.contains(TreeFlags::SOLID) as u64) << child);
+
+        self.binding.write_inner(parent, &[*parent_node]);
+    }
+}
+
+/// Merge sorted addresses into half-open ranges of consecutive addresses
+fn contiguous_ranges(addrs: &BTreeSet<Addr>) -> impl Iterator<Item = (Addr, Addr)> {
+    let mut addrs = addrs.iter().copied().peekable();
+    std::iter::from_fn(move || {
+        let start = addrs.next()?;
+        let mut end = start + 1;
+        while addrs.next_if_eq(&end).is_some() {
+            end += 1;
+        }
+        Some((start, end))
+    })
 }
@@ -0,0 +1,334 @@
+use glam::Vec3;
+
+use super::{
+    AIR, Addr, ChildIndex, Contree, ContreeError,
+    node_management::{Child, TreeFlags},
+    util::*,
+};
+
+impl Contree<'_> {
+    /// Clear the voxel at a position, freeing any nodes left empty
+    ///
+    /// Returns the material that was removed, positions out of bounds are always empty
+    pub fn remove(&mut self, pos: Vec3) -> Result<Option<u8>, ContreeError> {
+        if !self.in_bounds(pos) {
+            return Ok(None);
+        }
+
+        let code = self.locate(pos)?;
+        self.expand_path(code, AIR)?;
+        self.record(pos, AIR)?;
+        let mut path: Vec<(Addr, ChildIndex)> = Vec::new();
+        let Some(mut parent_address) = self.root else {
+            return Ok(None);
+        };
+
+        for i in self.first_morton_index()..MAX_MORTON_INDEX {
+            let child_index = morton_index(code, i).ok_or(ContreeError::InvalidSize(self.size))?;
+            path.push((parent_address, child_index));
+
+            match self.inners[parent_address as usize].child(child_index) {
+                Child::Empty => return Ok(None),
+                // expanding the path splits every solid node on it
+                Child::Solid(_) => {
+                    return Err(ContreeError::CorruptedLink {
+                        parent: parent_address,
+                        index: child_index,
+                    });
+                }
+                Child::Inner(addr) => parent_address = addr,
+                Child::Leaf(leaf_addr) => {
+                    let index = morton_index(code, MAX_MORTON_INDEX)
+                        .ok_or(ContreeError::InvalidSize(self.size))?;
+                    let leaf = &mut self.leaves[leaf_addr as usize];
+                    if (leaf.contains >> index) & 1 == 0 {
+                        return Ok(None);
+                    }
+
+                    let material = leaf.children[index as usize];
+                    leaf.contains &= !(1 << index);
+                    leaf.light &= !(1 << index);
+                    leaf.children[index as usize] = AIR;
+
+                    let emptied = leaf.contains == 0;
+                    if emptied {
+                        self.free_leaf_node(leaf_addr);
+                        self.prune_path(path);
+                    } else {
+                        self.binding.write_leaf(leaf_addr, &[*leaf]);
+                    }
+                    self.update_path(code);
+                    if emptied && self.shrink_on_remove {
+                        self.shrink_to_fit();
+                    }
+                    self.check_invariants();
+                    return Ok(Some(material));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Set the voxel at a position, removing it if the material is air
+    ///
+    /// Returns the material previously at the position
+    pub fn set(&mut self, pos: Vec3, material: u8) -> Result<Option<u8>, ContreeError> {
+        if material == AIR {
+            return self.remove(pos);
+        }
+
+        let previous = if self.in_bounds(pos) {
+            self.find(pos)?.material
+        } else {
+            None
+        };
+        self.insert(pos, material)?;
+        Ok(previous)
+    }
+
+    /// Promote the root's only child to root for as long as there is exactly one
+    ///
+    /// Undoes growth once the data no longer needs it, the root never becomes a leaf
+    pub fn shrink_to_fit(&mut self) {
+        while let Some(root) = self.root {
+            let node = self.inners[root as usize];
+            if node.contains.count_ones() != 1 {
+                break;
+            }
+
+            let index = node.contains.trailing_zeros() as ChildIndex;
+            let Child::Inner(child) = node.child(index) else {
+                break;
+            };
+
+            let child_size = self.size / 4;
+            self.center_offset += (child_offset(index) * child_size + child_size / 2).as_dvec3()
+                - (self.size / 2) as f64;
+            self.size = child_size;
+            self.root = Some(child);
+            self.free_inner_node(root);
+            self.write_bounds();
+        }
+        self.check_invariants();
+    }
+
+    /// Unlink the last child on the path, then free every ancestor left without children
+    ///
+    /// The root is never freed
+    fn prune_path(&mut self, mut path: Vec<(Addr, ChildIndex)>) {
+        while let Some((parent, child_index)) = path.pop() {
+            self.clear_parent_bitflags(
+                parent,
+                child_index,
+                TreeFlags::EXISTS | TreeFlags::LEAF | TreeFlags::LIGHT,
+            );
+
+            if self.inners[parent as usize].contains != 0 || Some(parent) == self.root {
+                break;
+            }
+            self.free_inner_node(parent);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::{DVec3, IVec3};
+
+    use super::*;
+    use crate::test_util::{WriteLog, corrupt_root_link, create_contree};
+
+    #[test]
+    fn remove_returns_material() {
+        let p = Vec3::new(1., 2., 3.);
+        let mut contree = create_contree(64, p);
+
+        assert_eq!(contree.remove(p).unwrap(), Some(10));
+        assert_eq!(contree.find(p).unwrap().material, None);
+        assert_eq!(contree.remove(p).unwrap(), None);
+    }
+
+    #[test]
+    fn remove_empty() {
+        let mut contree = create_contree(64, Vec3::ZERO);
+
+        assert_eq!(contree.remove(Vec3::splat(-20.)).unwrap(), None);
+        assert_eq!(contree.remove(Vec3::new(1., 0., 0.)).unwrap(), None);
+        assert_eq!(contree.remove(Vec3::splat(1000.)).unwrap(), None);
+        assert_eq!(contree.find(Vec3::ZERO).unwrap().material, Some(10));
+    }
+
+    #[test]
+    fn remove_keeps_siblings() {
+        let mut contree = create_contree(64, Vec3::ZERO);
+        contree.insert(Vec3::new(1., 0., 0.), 3).unwrap();
+
+        assert_eq!(contree.remove(Vec3::ZERO).unwrap(), Some(10));
+        assert_eq!(
+            contree.find(Vec3::new(1., 0., 0.)).unwrap().material,
+            Some(3)
+        );
+        assert!(contree.leaf_tombstones.is_empty());
+        assert!(contree.inner_tombstones.is_empty());
+    }
+
+    #[test]
+    fn remove_prunes_empty_nodes() {
+        let p = Vec3::ZERO;
+        let mut contree = create_contree(64, p);
+        let root = contree.root.unwrap();
+
+        contree.remove(p).unwrap();
+
+        assert_eq!(contree.leaf_tombstones, &[0]);
+        assert_eq!(contree.inner_tombstones, &[1]);
+        assert_eq!(contree.inners[root as usize].contains, 0);
+        assert_eq!(contree.inners[root as usize].leaf, 0);
+        assert_eq!(contree.find(p).unwrap().parent_address, root);
+
+        // freed nodes are reused
+        contree.insert(p, 4).unwrap();
+        assert!(contree.leaf_tombstones.is_empty());
+        assert!(contree.inner_tombstones.is_empty());
+        assert_eq!(contree.leaves.len(), 1);
+        assert_eq!(contree.inners.len(), 2);
+        assert_eq!(contree.find(p).unwrap().material, Some(4));
+    }
+
+    #[test]
+    fn remove_reports_touched_nodes() {
+        let log = WriteLog::default();
+        let mut contree = Contree::new(&log).unwrap();
+        contree.size = 64;
+        contree.insert(Vec3::ZERO, 10).unwrap();
+        contree.insert(Vec3::new(1., 0., 0.), 3).unwrap();
+        log.clear();
+
+        contree.remove(Vec3::ZERO).unwrap();
+        assert!(log.inners.lock().unwrap().is_empty());
+        assert_eq!(log.leaves.lock().unwrap().len(), 1);
+        assert_eq!(
+            log.leaves.lock().unwrap()[0].1[0].contains,
+            contree.leaves[0].contains
+        );
+
+        contree.remove(Vec3::new(1., 0., 0.)).unwrap();
+        let root = contree.root.unwrap();
+        let written = log.inners.lock().unwrap();
+        assert!(
+            written
+                .iter()
+                .any(|(addr, nodes)| *addr == root && nodes[0].contains == 0)
+        );
+    }
+
+    #[test]
+    fn remove_reports_frees_and_bounds() {
+        let log = WriteLog::default();
+        let mut contree = Contree::new(&log).unwrap();
+        contree.insert(Vec3::ZERO, 10).unwrap();
+        contree.insert(Vec3::splat(100.), 3).unwrap();
+        let grown = contree.root;
+        assert_eq!(
+            log.bounds.lock().unwrap().last(),
+            Some(&(grown, contree.size, contree.center_offset))
+        );
+        log.bounds.lock().unwrap().clear();
+
+        contree.shrink_on_remove = true;
+        contree.remove(Vec3::splat(100.)).unwrap();
+        assert_eq!(*log.freed_leaves.lock().unwrap(), contree.leaf_tombstones);
+        assert_eq!(*log.freed_inners.lock().unwrap(), contree.inner_tombstones);
+        assert!(log.freed_inners.lock().unwrap().contains(&grown.unwrap()));
+        assert_eq!(
+            log.bounds.lock().unwrap().last(),
+            Some(&(contree.root, 16, contree.center_offset))
+        );
+    }
+
+    #[test]
+    fn shrink_after_removal() {
+        let p = Vec3::new(1., 2., 3.);
+        let q = Vec3::new(-100., 50., 20.);
+        let mut contree = create_contree(256, p);
+        contree.insert(q, 4).unwrap();
+
+        contree.shrink_to_fit();
+        assert_eq!(contree.size, 256);
+
+        contree.remove(q).unwrap();
+        contree.shrink_to_fit();
+        assert_eq!(contree.size, 16);
+        assert_eq!(contree.center_offset, DVec3::splat(8.));
+        assert_eq!(contree.inner_tombstones.len(), 4);
+        assert_eq!(contree.find(p).unwrap().material, Some(10));
+        assert!(contree.in_bounds(Vec3::ZERO));
+        assert!(!contree.in_bounds(Vec3::splat(-1.)));
+    }
+
+    #[test]
+    fn shrink_on_remove() {
+        let p = Vec3::splat(-30.);
+        let q = Vec3::splat(30.);
+        let mut contree = create_contree(64, p);
+        contree.insert(q, 3).unwrap();
+        contree.shrink_on_remove = true;
+
+        contree.remove(p).unwrap();
+        assert_eq!(contree.size, 16);
+        assert_eq!(contree.center_offset, DVec3::splat(24.));
+        assert_eq!(contree.find(q).unwrap().material, Some(3));
+
+        // the root is never replaced by a leaf
+        contree.remove(q).unwrap();
+        assert_eq!(contree.size, 16);
+        assert!(contree.root.is_some());
+    }
+
+    #[test]
+    fn set_air_removes() {
+        let p = Vec3::splat(2.);
+        let mut contree = create_contree(64, p);
+
+        assert_eq!(contree.set(p, 7).unwrap(), Some(10));
+        assert_eq!(contree.find(p).unwrap().material, Some(7));
+        assert_eq!(contree.set(p, AIR).unwrap(), Some(7));
+        assert_eq!(contree.find(p).unwrap().material, None);
+        assert_eq!(contree.set(p, 2).unwrap(), None);
+    }
+
+    #[test]
+    fn remove_splits_solid() {
+        let mut contree = create_contree(64, Vec3::splat(-20.));
+        contree
+            .fill_aabb(IVec3::splat(-16), IVec3::splat(15), 3)
+            .unwrap();
+        let solid = contree.inners[contree.root.unwrap() as usize].solid;
+
+        assert_eq!(contree.remove(Vec3::new(4., -3., 9.)).unwrap(), Some(3));
+        assert_eq!(contree.find(Vec3::new(4., -3., 9.)).unwrap().material, None);
+        assert_eq!(
+            contree.find(Vec3::new(4., -3., 8.)).unwrap().material,
+            Some(3)
+        );
+        assert_eq!(
+            contree.inners[contree.root.unwrap() as usize]
+                .solid
+                .count_ones(),
+            solid.count_ones() - 1
+        );
+
+        // filling the hole back in collapses the nodes again
+        contree.insert(Vec3::new(4., -3., 9.), 3).unwrap();
+        assert_eq!(contree.inners[contree.root.unwrap() as usize].solid, solid);
+    }
+
+    #[test]
+    fn remove_reports_corrupted_tree() {
+        let mut contree = create_contree(64, Vec3::ZERO);
+        let corrupted = corrupt_root_link(&mut contree);
+
+        assert_eq!(contree.remove(Vec3::ZERO), Err(corrupted));
+        assert_eq!(contree.leaves[0].contains.count_ones(), 1);
+    }
+}
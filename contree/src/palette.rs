@@ -0,0 +1,258 @@
+use serde::{Deserialize, Serialize};
+
+use super::{AIR, Contree, Material};
+
+/// Most materials a palette can hold, one for every value of a voxel
+pub const MAX_MATERIALS: usize = u8::MAX as usize + 1;
+
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum PaletteError {
+    #[error("palette already holds {MAX_MATERIALS} materials")]
+    Full,
+    #[error("a material named {0:?} already exists")]
+    DuplicateName(String),
+    #[error("no material at index {0}")]
+    Missing(u8),
+    #[error("air cannot be changed")]
+    Air,
+}
+
+impl Material {
+    /// Material of empty space, fully transparent
+    pub const AIR: Self = Self::new([0.; 4], 0.);
+
+    pub const fn new(color: [f32; 4], reflectivity: f32) -> Self {
+        Self {
+            color,
+            reflectivity,
+            emission: 0.,
+            padding: [0; 8],
+        }
+    }
+
+    pub const fn with_emission(self, emission: f32) -> Self {
+        Self { emission, ..self }
+    }
+
+    pub fn is_emissive(&self) -> bool {
+        self.emission > 0.
+    }
+}
+
+/// Named materials indexed by the `u8` stored in each voxel
+///
+/// Index 0 is always [`Material::AIR`]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Palette {
+    names: Vec<String>,
+    materials: Vec<Material>,
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Self {
+            names: vec!["air".to_string()],
+            materials: vec![Material::AIR],
+        }
+    }
+}
+
+impl Palette {
+    /// Add a material under a new name, returning its index
+    pub fn add(&mut self, name: impl Into<String>, material: Material) -> Result<u8, PaletteError> {
+        let name = name.into();
+        if self.materials.len() == MAX_MATERIALS {
+            return Err(PaletteError::Full);
+        }
+        if self.index_of(&name).is_some() {
+            return Err(PaletteError::DuplicateName(name));
+        }
+
+        self.names.push(name);
+        self.materials.push(material);
+        Ok((self.materials.len() - 1) as u8)
+    }
+
+    /// Replace the material at an index, keeping its name
+    pub fn set(&mut self, index: u8, material: Material) -> Result<(), PaletteError> {
+        if index == AIR {
+            return Err(PaletteError::Air);
+        }
+        *self
+            .materials
+            .get_mut(index as usize)
+            .ok_or(PaletteError::Missing(index))? = material;
+        Ok(())
+    }
+
+    pub fn get(&self, index: u8) -> Option<&Material> {
+        self.materials.get(index as usize)
+    }
+
+    pub fn name(&self, index: u8) -> Option<&str> {
+        self.names.get(index as usize).map(String::as_str)
+    }
+
+    /// Whether voxels of a material are light sources, unknown materials are not
+    pub fn is_emissive(&self, index: u8) -> bool {
+        self.get(index).is_some_and(Material::is_emissive)
+    }
+
+    pub fn index_of(&self, name: &str) -> Option<u8> {
+        self.names.iter().position(|n| n == name).map(|i| i as u8)
+    }
+
+    /// Number of materials, including air
+    pub fn len(&self) -> usize {
+        self.materials.len()
+    }
+
+    /// Always false, since air is always present
+    pub fn is_empty(&self) -> bool {
+        self.materials.is_empty()
+    }
+
+    /// Every material in index order, as laid out on the GPU
+    pub fn materials(&self) -> &[Material] {
+        &self.materials
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (u8, &str, &Material)> {
+        self.names
+            .iter()
+            .zip(&self.materials)
+            .enumerate()
+            .map(|(i, (name, material))| (i as u8, name.as_str(), material))
+    }
+
+    /// Whether the palette could have been built through [`Palette::add`]
+    pub(crate) fn is_valid(&self) -> bool {
+        (1..=MAX_MATERIALS).contains(&self.materials.len())
+            && self.names.len() == self.materials.len()
+            && self.materials[AIR as usize] == Material::AIR
+    }
+}
+
+impl Contree<'_> {
+    /// Replace the palette and upload it, relighting the tree if any emission changed
+    pub fn set_palette(&mut self, palette: Palette) {
+        let relight = (0..=u8::MAX).any(|i| self.palette.is_emissive(i) != palette.is_emissive(i));
+        self.palette = palette;
+        self.write_palette();
+        if relight {
+            self.refresh_lights();
+        }
+    }
+
+    /// Add a material to the palette and upload it, returning its index
+    pub fn add_material(
+        &mut self,
+        name: impl Into<String>,
+        material: Material,
+    ) -> Result<u8, PaletteError> {
+        let index = self.palette.add(name, material)?;
+        self.write_palette();
+        Ok(index)
+    }
+
+    /// Replace a material in the palette and upload it, relighting the tree if its emission changed
+    pub fn set_material(&mut self, index: u8, material: Material) -> Result<(), PaletteError> {
+        let relight = self.palette.is_emissive(index) != material.is_emissive();
+        self.palette.set(index, material)?;
+        self.write_palette();
+        if relight {
+            self.refresh_lights();
+        }
+        Ok(())
+    }
+
+    /// Upload the whole palette, needed after changing [`Contree::palette`] directly
+    pub fn write_palette(&self) {
+        self.binding.write_materials(self.palette.materials());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::{Addr, ContreeInner, ContreeLeaf, GPUBindable};
+
+    #[derive(Debug, Default)]
+    struct MaterialLog {
+        uploads: Mutex<Vec<Vec<Material>>>,
+    }
+    impl GPUBindable for MaterialLog {
+        fn write_inner(&self, _: Addr, _: &[ContreeInner]) {}
+        fn write_leaf(&self, _: Addr, _: &[ContreeLeaf]) {}
+        fn write_materials(&self, data: &[Material]) {
+            self.uploads.lock().unwrap().push(data.to_vec());
+        }
+    }
+
+    fn stone() -> Material {
+        Material::new([0.5, 0.5, 0.5, 1.], 0.1)
+    }
+
+    #[test]
+    fn palette_starts_with_air() {
+        let palette = Palette::default();
+
+        assert_eq!(palette.len(), 1);
+        assert_eq!(palette.get(AIR), Some(&Material::AIR));
+        assert_eq!(palette.name(AIR), Some("air"));
+        assert_eq!(palette.index_of("air"), Some(AIR));
+    }
+
+    #[test]
+    fn palette_add_and_set() {
+        let mut palette = Palette::default();
+        let index = palette.add("stone", stone()).unwrap();
+
+        assert_eq!(index, 1);
+        assert_eq!(palette.get(index), Some(&stone()));
+        assert_eq!(palette.index_of("stone"), Some(1));
+        assert_eq!(
+            palette.add("stone", stone()),
+            Err(PaletteError::DuplicateName("stone".to_string()))
+        );
+
+        let glass = Material::new([1., 1., 1., 0.2], 0.9);
+        palette.set(index, glass).unwrap();
+        assert_eq!(palette.get(index), Some(&glass));
+        assert_eq!(palette.name(index), Some("stone"));
+        assert_eq!(palette.set(AIR, glass), Err(PaletteError::Air));
+        assert_eq!(palette.set(2, glass), Err(PaletteError::Missing(2)));
+    }
+
+    #[test]
+    fn palette_full() {
+        let mut palette = Palette::default();
+        for i in 1..MAX_MATERIALS {
+            assert_eq!(palette.add(format!("m{i}"), stone()), Ok(i as u8));
+        }
+
+        assert_eq!(
+            palette.add("one too many", stone()),
+            Err(PaletteError::Full)
+        );
+        assert_eq!(palette.len(), MAX_MATERIALS);
+    }
+
+    #[test]
+    fn palette_changes_are_uploaded() {
+        let log = MaterialLog::default();
+        let mut contree = Contree::new(&log).unwrap();
+        let index = contree.add_material("stone", stone()).unwrap();
+        contree.set_material(index, Material::AIR).unwrap();
+        contree.set_palette(Palette::default());
+
+        let uploads = log.uploads.lock().unwrap();
+        assert_eq!(uploads.len(), 4);
+        assert_eq!(uploads[0], &[Material::AIR]);
+        assert_eq!(uploads[1], &[Material::AIR, stone()]);
+        assert_eq!(uploads[2], &[Material::AIR, Material::AIR]);
+        assert_eq!(uploads[3], &[Material::AIR]);
+    }
+}
@@ -0,0 +1,139 @@
+use glam::{IVec3, Vec3};
+
+use super::{Contree, iteration::Voxels};
+
+impl Contree<'_> {
+    /// Every occupied voxel between two corners (inclusive) and its material, in morton order
+    pub fn query_aabb(&self, min: IVec3, max: IVec3) -> impl Iterator<Item = (IVec3, u8)> + '_ {
+        let lo = self.normalize_voxel(min);
+        let hi = self.normalize_voxel(max);
+        Voxels::new(self, move |node_min, size| {
+            let node_min = node_min.as_ivec3();
+            node_min.cmple(hi).all() && (node_min + (size as i32 - 1)).cmpge(lo).all()
+        })
+    }
+
+    /// Every occupied voxel whose center is within a radius and its material, in morton order
+    ///
+    /// A negative or NaN radius contains nothing.
+    pub fn query_sphere(
+        &self,
+        center: Vec3,
+        radius: f32,
+    ) -> impl Iterator<Item = (IVec3, u8)> + '_ {
+        // normalizing shifts every voxel position by the same amount
+        let norm_center = center.as_dvec3() + self.normalize_voxel(IVec3::ZERO).as_dvec3();
+        // NaN fails the comparison as well
+        let valid = radius >= 0.;
+        let radius_squared = (radius as f64).powi(2);
+        Voxels::new(self, move |node_min, size| {
+            if !valid {
+                return false;
+            }
+            let node_min = node_min.as_dvec3();
+            let closest = norm_center.clamp(node_min, node_min + (size - 1) as f64);
+            closest.distance_squared(norm_center) <= radius_squared
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::DVec3;
+
+    use super::*;
+    use crate::test_util::create_contree;
+
+    fn scattered_contree() -> Contree<'static> {
+        let mut contree = create_contree(256, Vec3::ZERO);
+        contree.center_offset = DVec3::new(8., -4., 0.);
+        for i in -40..40 {
+            let p = Vec3::new((i * 3) as f32, ((i * 7) % 50) as f32, ((i * i) % 60) as f32);
+            contree.insert(p, i as u8).unwrap();
+        }
+        contree
+            .fill_aabb(IVec3::new(-10, -10, -10), IVec3::new(-2, 3, -6), 4)
+            .unwrap();
+        contree
+    }
+
+    #[test]
+    fn query_aabb_matches_filter() {
+        let contree = scattered_contree();
+        let (min, max) = (IVec3::new(-30, -8, -9), IVec3::new(20, 40, 30));
+
+        let expected: Vec<_> = contree
+            .iter()
+            .filter(|(p, _)| p.cmpge(min).all() && p.cmple(max).all())
+            .collect();
+        assert!(!expected.is_empty());
+        assert_eq!(contree.query_aabb(min, max).collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn query_aabb_outside() {
+        let contree = scattered_contree();
+
+        assert_eq!(
+            contree
+                .query_aabb(IVec3::splat(500), IVec3::splat(600))
+                .count(),
+            0
+        );
+        assert_eq!(
+            contree
+                .query_aabb(IVec3::splat(5), IVec3::splat(-5))
+                .count(),
+            0
+        );
+    }
+
+    #[test]
+    fn query_sphere_matches_filter() {
+        let mut contree = scattered_contree();
+        let spheres = [
+            (Vec3::ZERO, 20.),
+            (Vec3::new(-6.5, 0., -8.), 3.5),
+            (Vec3::new(60., 30., 20.), 45.),
+            (Vec3::splat(-4.), 0.),
+        ];
+
+        // a fractional center moves voxels off the grid the positions are rounded to
+        for offset in [DVec3::ZERO, DVec3::new(0.5, 0.25, -0.75)] {
+            contree.center_offset += offset;
+            for (center, radius) in spheres {
+                let expected: Vec<_> = contree
+                    .iter()
+                    .filter(|(p, _)| p.as_vec3().distance(center) <= radius)
+                    .collect();
+                assert_eq!(
+                    contree.query_sphere(center, radius).collect::<Vec<_>>(),
+                    expected
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn query_sphere_single_voxel() {
+        let contree = create_contree(64, Vec3::new(3., 4., 5.));
+
+        assert_eq!(
+            contree
+                .query_sphere(Vec3::new(3., 4., 6.), 1.)
+                .collect::<Vec<_>>(),
+            &[(IVec3::new(3, 4, 5), 10)]
+        );
+        assert_eq!(contree.query_sphere(Vec3::new(3., 4., 6.), 0.9).count(), 0);
+    }
+
+    #[test]
+    fn query_sphere_invalid_radius() {
+        let contree = scattered_contree();
+        let center = Vec3::ZERO;
+        assert!(contree.query_sphere(center, 3.).count() > 0);
+
+        assert_eq!(contree.query_sphere(center, -3.).count(), 0);
+        assert_eq!(contree.query_sphere(center, f32::NAN).count(), 0);
+    }
+}
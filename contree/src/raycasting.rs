@@ -1,96 +1,366 @@
-use super::{Contree, finding::FindResult, util::*};
-use glam::Vec3;
+use super::{AIR, Addr, Contree, ContreeError, node_management::Child, util::*};
+use glam::{DVec3, IVec3, UVec3, Vec3};
+use rayon::prelude::*;
+
+/// Where and how a ray struck a voxel
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RaycastHit {
+    /// Position of the voxel that was hit
+    pub voxel: IVec3,
+    /// Normal of the face the ray entered through, zero if it started inside the voxel
+    pub normal: IVec3,
+    pub material: u8,
+    /// Distance travelled from the origin of the ray
+    pub distance: f32,
+    /// Point on the surface of the voxel where the ray entered
+    pub position: Vec3,
+    /// Number of traversal steps taken
+    pub steps: u32,
+}
+
+#[derive(Clone, Copy)]
+pub struct RaycastOptions<'f> {
+    /// Rays give up after travelling this far
+    pub max_distance: f32,
+    /// Whether a material stops the ray, anything but air does by default
+    pub stops_on: Option<&'f (dyn Fn(u8) -> bool + Sync)>,
+}
+
+impl Default for RaycastOptions<'_> {
+    fn default() -> Self {
+        Self {
+            max_distance: f32::INFINITY,
+            stops_on: None,
+        }
+    }
+}
+
+impl RaycastOptions<'_> {
+    fn stops_on(&self, material: u8) -> bool {
+        match self.stops_on {
+            Some(stops_on) => stops_on(material),
+            None => material != AIR,
+        }
+    }
+}
+
+/// What a ray finds in the cell it is in, along with the largest node around the cell
+/// that is the same throughout, in normalized coordinates
+#[derive(Debug, Clone, Copy)]
+enum Cell {
+    Filled { material: u8, min: UVec3, size: u32 },
+    Empty { min: UVec3, size: u32 },
+}
+
+/// Inner nodes from the root down to the last cell looked up, as `(addr, min, size)`
+///
+/// Consecutive lookups are usually close together, so they can start partway down the tree
+#[derive(Debug, Default)]
+struct Path {
+    nodes: Vec<(Addr, UVec3, u32)>,
+}
+
+/// Most rays traced by a single task of [`Contree::raycast_many`]
+const RAYS_PER_TASK: usize = 64;
 
 impl Contree<'_> {
-    pub fn raycast(&self, pos: Vec3, dir: Vec3) -> Option<Vec3> {
-        let norm_dir = dir.normalize();
-        let inv_norm_dir = norm_dir.recip();
-        let mut p = pos + 0.5 - self.center_offset;
-        let dir_pos = dir
-            .map(|v| if v == 0. { 0. } else { v.signum() })
-            .max(Vec3::ZERO);
-
-        if !self.in_bounds(p - 0.5 + self.center_offset) {
-            let boundary = Vec3::splat((self.size / 2) as f32) * p.signum();
-            p += ((boundary - p) * inv_norm_dir)
-                .to_array()
-                .into_iter()
-                .filter(|x| x.is_normal())
-                .reduce(f32::max)?
-                * norm_dir;
-        }
-
-        let mut find_p = p + (norm_dir * 0.00001);
-        while self.in_bounds(find_p - 0.5 + self.center_offset) {
-            let FindResult {
-                leaf_address,
-                parent_address,
-                traversal_state: (code, next_morton_index),
-                depth,
-                ..
-            } = self.find(find_p - 0.5 + self.center_offset)?;
-
-            // break if hit solid
-            if let Some(laddr) = leaf_address
-                && let Some(cidx) = morton_index(code, next_morton_index)
-                && self.leaves[laddr as usize].contains & (0b1 << cidx) != 0
-                && self.leaves[laddr as usize].children[cidx as usize] != 0
-            {
-                return Some(p - 0.5 + self.center_offset);
+    /// Cast a ray, returning the first voxel it hits
+    ///
+    /// Fails if the origin is not finite or the ray runs into a corrupted node
+    pub fn raycast(&self, pos: Vec3, dir: Vec3) -> Result<Option<RaycastHit>, ContreeError> {
+        self.raycast_with(pos, dir, &RaycastOptions::default())
+    }
+
+    /// Cast a ray using a hierarchical DDA over integer voxel coordinates
+    ///
+    /// Empty nodes are crossed in a single step, and every boundary crossing is computed from
+    /// integer cell bounds rather than accumulated, so results do not depend on float drift.
+    /// Cells are half-open, a ray passing exactly through an edge or corner enters the cells
+    /// on the positive side of it first.
+    pub fn raycast_with(
+        &self,
+        pos: Vec3,
+        dir: Vec3,
+        options: &RaycastOptions,
+    ) -> Result<Option<RaycastHit>, ContreeError> {
+        self.raycast_along(pos, dir, options, &mut Path::default())
+    }
+
+    /// Cast many `(pos, dir)` rays in parallel, returning the hits in the same order
+    ///
+    /// Fails if any of the rays does
+    pub fn raycast_many(
+        &self,
+        rays: &[(Vec3, Vec3)],
+    ) -> Result<Vec<Option<RaycastHit>>, ContreeError> {
+        self.raycast_many_with(rays, &RaycastOptions::default())
+    }
+
+    /// Cast many `(pos, dir)` rays in parallel with the given options
+    ///
+    /// Rays sharing an origin are traced together so the nodes around it are only found once.
+    pub fn raycast_many_with(
+        &self,
+        rays: &[(Vec3, Vec3)],
+        options: &RaycastOptions,
+    ) -> Result<Vec<Option<RaycastHit>>, ContreeError> {
+        let origin_key = |i: &usize| rays[*i].0.to_array().map(f32::to_bits);
+        let mut order: Vec<usize> = (0..rays.len()).collect();
+        order.par_sort_unstable_by_key(origin_key);
+
+        let tasks: Vec<&[usize]> = order
+            .chunk_by(|a, b| origin_key(a) == origin_key(b))
+            .flat_map(|group| group.chunks(RAYS_PER_TASK))
+            .collect();
+        let traced: Vec<(usize, Result<Option<RaycastHit>, ContreeError>)> = tasks
+            .into_par_iter()
+            .flat_map_iter(|task| {
+                let mut path = Path::default();
+                task.iter()
+                    .map(|&i| {
+                        let (pos, dir) = rays[i];
+                        (i, self.raycast_along(pos, dir, options, &mut path))
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+
+        let mut hits = vec![None; rays.len()];
+        for (i, hit) in traced {
+            hits[i] = hit?;
+        }
+        Ok(hits)
+    }
+
+    fn raycast_along(
+        &self,
+        pos: Vec3,
+        dir: Vec3,
+        options: &RaycastOptions,
+        path: &mut Path,
+    ) -> Result<Option<RaycastHit>, ContreeError> {
+        if !pos.is_finite() {
+            return Err(ContreeError::OutOfRange(pos));
+        }
+        Self::check_size(self.size)?;
+        let Some(root) = self.root else {
+            return Ok(None);
+        };
+        if root as usize >= self.inners.len() {
+            return Err(ContreeError::NoRoot);
+        }
+        if dir == Vec3::ZERO || !dir.is_finite() {
+            return Ok(None);
+        }
+        // traced in f64 like every other normalization, see `Contree::tree_coords`
+        let dir = dir.as_dvec3();
+        let size = self.size as f64;
+        let origin = self.tree_coords(pos.as_dvec3());
+        let dir_len = dir.length();
+
+        // clip the ray to the bounds of the tree
+        let mut t = 0_f64;
+        let mut t_exit = f64::INFINITY;
+        let mut entry_axis = None;
+        for axis in 0..3 {
+            if dir[axis] == 0. {
+                if origin[axis] < 0. || origin[axis] >= size {
+                    return Ok(None);
+                }
+                continue;
             }
+            let (near, far) = if dir[axis] > 0. {
+                (0., size)
+            } else {
+                (size, 0.)
+            };
+            let t_near = (near - origin[axis]) / dir[axis];
+            let t_far = (far - origin[axis]) / dir[axis];
+            if t_near > t {
+                t = t_near;
+                entry_axis = Some(axis);
+            }
+            t_exit = t_exit.min(t_far);
+        }
+        if t >= t_exit {
+            return Ok(None);
+        }
 
-            // When moving in a node, unless you know it has no children, you can only move 1/4 at a time
-            let child_size =
-                if leaf_address.is_some() || self.inners[parent_address as usize].contains != 0 {
-                    self.size >> ((depth + 1) << 1)
-                } else {
-                    self.size >> (depth << 1)
-                } as f32;
-            let boundary = child_size * ((find_p / child_size).floor() + dir_pos);
+        let mut normal = IVec3::ZERO;
+        let mut cell = (origin + t * dir)
+            .floor()
+            .clamp(DVec3::ZERO, DVec3::splat(size - 1.))
+            .as_uvec3();
+        if let Some(axis) = entry_axis {
+            cell[axis] = if dir[axis] > 0. { 0 } else { self.size - 1 };
+            normal = face_normal(axis, dir);
+        }
 
-            // Maximum t before hitting boundary on each axis
-            let max_t = (boundary - p) * inv_norm_dir;
+        let mut steps = 0;
+        loop {
+            if t * dir_len > options.max_distance as f64 {
+                return Ok(None);
+            }
+            steps += 1;
+
+            let (min, node_size) = match self.cell_at(root, cell, path)? {
+                Cell::Filled { material, .. } if options.stops_on(material) => {
+                    return Ok(Some(RaycastHit {
+                        voxel: self.denormalize_voxel(cell),
+                        normal,
+                        material,
+                        distance: (t * dir_len) as f32,
+                        position: self.world_coords(origin + t * dir).as_vec3(),
+                        steps,
+                    }));
+                }
+                Cell::Filled { min, size, .. } | Cell::Empty { min, size } => (min, size),
+            };
 
-            // WARN: May have platform-dependent behavior
-            p += max_t.abs().min_element() * norm_dir;
+            // t at which the ray leaves the node on each axis
+            let bounds = DVec3::select(
+                dir.cmpgt(DVec3::ZERO),
+                (min + node_size).as_dvec3(),
+                min.as_dvec3(),
+            );
+            let t_axes = DVec3::select(
+                dir.cmpeq(DVec3::ZERO),
+                DVec3::INFINITY,
+                (bounds - origin) / dir,
+            );
+            let t_next = t_axes.min_element();
+            let crossed = t_axes.cmpeq(DVec3::splat(t_next));
+            // a point on a boundary belongs to the cell above it, so when several axes are
+            // crossed at once only the positive ones move, the others are crossed next step
+            let positive = crossed & dir.cmpgt(DVec3::ZERO);
+            let stepped = if positive.any() { positive } else { crossed };
 
-            find_p = p + (norm_dir * 0.00001);
+            let mut next = IVec3::ZERO;
+            for axis in (0..3).rev() {
+                next[axis] = if stepped.test(axis) {
+                    normal = face_normal(axis, dir);
+                    if dir[axis] > 0. {
+                        (min[axis] + node_size) as i32
+                    } else {
+                        min[axis] as i32 - 1
+                    }
+                } else {
+                    // stays inside the node, clamped in case of rounding
+                    ((origin[axis] + t_next * dir[axis]).floor() as i32)
+                        .clamp(min[axis] as i32, (min[axis] + node_size - 1) as i32)
+                };
+            }
+            if next.cmplt(IVec3::ZERO).any() || next.cmpge(IVec3::splat(self.size as i32)).any() {
+                return Ok(None);
+            }
+            cell = next.as_uvec3();
+            t = t.max(t_next);
+        }
+    }
+
+    /// Voxel at a normalized position, or the largest empty or solid node containing it
+    ///
+    /// The lookup starts from the deepest node of `path` containing the cell rather than the root
+    fn cell_at(&self, root: Addr, cell: UVec3, path: &mut Path) -> Result<Cell, ContreeError> {
+        if path.nodes.is_empty() {
+            path.nodes.push((root, UVec3::ZERO, self.size));
+        }
+        while let &[.., _, (_, min, size)] = path.nodes.as_slice()
+            && !(min.cmple(cell).all() && cell.cmplt(min + size).all())
+        {
+            path.nodes.pop();
+        }
+
+        let code = morton_code(cell);
+        let first = self.first_morton_index();
+        loop {
+            let (addr, _, size) = *path.nodes.last().unwrap();
+            let index = first + path.nodes.len() as u8 - 1;
+            let child_size = size / 4;
+            let child_index = morton_index(code, index).unwrap();
+            let corrupted = ContreeError::CorruptedLink {
+                parent: addr,
+                index: child_index,
+            };
+            match self.inners[addr as usize].child(child_index) {
+                // only leaves and solid children sit at the bottom of the tree
+                Child::Inner(child) if child_size == 4 || child as usize >= self.inners.len() => {
+                    return Err(corrupted);
+                }
+                Child::Inner(child) => {
+                    path.nodes
+                        .push((child, cell & !(child_size - 1), child_size));
+                }
+                Child::Leaf(leaf) => {
+                    let leaf = self.leaves.get(leaf as usize).ok_or(corrupted)?;
+                    let i = morton_index(code, MAX_MORTON_INDEX).unwrap();
+                    return Ok(if (leaf.contains >> i) & 1 == 1 {
+                        Cell::Filled {
+                            material: leaf.children[i as usize],
+                            min: cell,
+                            size: 1,
+                        }
+                    } else {
+                        Cell::Empty { min: cell, size: 1 }
+                    });
+                }
+                Child::Solid(material) => {
+                    return Ok(Cell::Filled {
+                        material,
+                        min: cell & !(child_size - 1),
+                        size: child_size,
+                    });
+                }
+                Child::Empty => {
+                    return Ok(Cell::Empty {
+                        min: cell & !(child_size - 1),
+                        size: child_size,
+                    });
+                }
+            }
         }
-        None
     }
 }
 
+/// Normal of the face a ray enters through when crossing an axis
+fn face_normal(axis: usize, dir: DVec3) -> IVec3 {
+    let mut normal = IVec3::ZERO;
+    normal[axis] = if dir[axis] > 0. { -1 } else { 1 };
+    normal
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-
-    fn create_contree(size: u32, p: Vec3) -> Contree<'static> {
-        assert!(size > 4, "The root node cannot be a leaf!");
-        let mut contree = Contree {
-            size,
-            ..Default::default()
-        };
-        contree.insert(p, 10);
-        contree
-    }
+    use crate::test_util::create_contree;
 
     #[test]
     fn raycast_in_bounds() {
         let contree = create_contree(64, Vec3::splat(-1.));
 
-        assert!(contree.raycast(Vec3::splat(0.), Vec3::splat(1.)).is_none());
+        assert!(
+            contree
+                .raycast(Vec3::splat(0.), Vec3::splat(1.))
+                .unwrap()
+                .is_none()
+        );
         assert_eq!(
-            contree.raycast(Vec3::splat(0.), Vec3::splat(-1.)),
+            contree
+                .raycast(Vec3::splat(0.), Vec3::splat(-1.))
+                .unwrap()
+                .map(|hit| hit.position),
             Some(Vec3::splat(-0.5))
         );
         assert!(
             contree
                 .raycast(Vec3::new(0., -30., 0.), Vec3::new(0., -1., 0.))
+                .unwrap()
                 .is_none(),
         );
         assert!(
             contree
                 .raycast(Vec3::new(0., -30., 0.), Vec3::new(0., 1., 0.))
+                .unwrap()
                 .is_none(),
         );
     }
@@ -100,12 +370,18 @@ mod tests {
         let contree = create_contree(64, Vec3::splat(0.));
 
         assert_eq!(
-            contree.raycast(Vec3::new(100., 50., 0.), -Vec3::new(2., 1., 0.)),
+            contree
+                .raycast(Vec3::new(100., 50., 0.), -Vec3::new(2., 1., 0.))
+                .unwrap()
+                .map(|hit| hit.position),
             Some(Vec3::new(0.5, 0.25, 0.))
         );
 
         assert_eq!(
-            contree.raycast(-Vec3::new(100., 50., 0.), Vec3::new(2., 1., 0.)),
+            contree
+                .raycast(-Vec3::new(100., 50., 0.), Vec3::new(2., 1., 0.))
+                .unwrap()
+                .map(|hit| hit.position),
             Some(-Vec3::new(0.5, 0.25, 0.))
         );
     }
@@ -117,6 +393,7 @@ mod tests {
         assert!(
             contree
                 .raycast(Vec3::new(-100., 0., 0.), Vec3::new(-1., 0., 0.))
+                .unwrap()
                 .is_none()
         );
     }
@@ -129,15 +406,246 @@ mod tests {
         let pos = Vec3::new(distance + 0.5, 0., 0.);
 
         let mut dir = Vec3::new(-distance, 1., 0.);
-        // TODO: Can this be (<=)?
-        while dir.y > -1. {
+        while dir.y >= -1. {
             if dir.y.abs() < 0.5 {
-                assert!(contree.raycast(pos, dir).is_some());
+                assert!(contree.raycast(pos, dir).unwrap().is_some());
             } else {
-                assert!(contree.raycast(pos, dir).is_none());
+                assert!(contree.raycast(pos, dir).unwrap().is_none());
             }
             // round to account for FPE
             dir = ((dir + Vec3::new(0., -0.0005, 0.)) / 0.0005).round() * 0.0005;
         }
     }
+
+    #[test]
+    fn raycast_hit_details() {
+        let mut contree = create_contree(64, Vec3::new(-3., 0., 0.));
+        contree.insert(Vec3::new(-3., 1., 0.), 4).unwrap();
+
+        let hit = contree
+            .raycast(Vec3::new(5., 0., 0.), Vec3::new(-1., 0., 0.))
+            .unwrap()
+            .unwrap();
+        assert_eq!(hit.voxel, IVec3::new(-3, 0, 0));
+        assert_eq!(hit.normal, IVec3::X);
+        assert_eq!(hit.material, 10);
+        assert_eq!(hit.position, Vec3::new(-2.5, 0., 0.));
+        assert_eq!(hit.distance, 7.5);
+        assert!(hit.steps > 0);
+
+        let hit = contree
+            .raycast(Vec3::new(-3., 20., 0.), Vec3::new(0., -1., 0.))
+            .unwrap()
+            .unwrap();
+        assert_eq!(hit.voxel, IVec3::new(-3, 1, 0));
+        assert_eq!(hit.normal, IVec3::Y);
+        assert_eq!(hit.material, 4);
+
+        let hit = contree
+            .raycast(Vec3::new(-100., 0., 0.), Vec3::new(1., 0., 0.))
+            .unwrap()
+            .unwrap();
+        assert_eq!(hit.normal, IVec3::NEG_X);
+        assert_eq!(hit.distance, 96.5);
+    }
+
+    #[test]
+    fn raycast_max_distance() {
+        let contree = create_contree(64, Vec3::new(-3., 0., 0.));
+        let options = |max_distance| RaycastOptions {
+            max_distance,
+            ..Default::default()
+        };
+
+        let (pos, dir) = (Vec3::new(5., 0., 0.), Vec3::new(-1., 0., 0.));
+        assert!(
+            contree
+                .raycast_with(pos, dir, &options(7.))
+                .unwrap()
+                .is_none()
+        );
+        assert!(
+            contree
+                .raycast_with(pos, dir, &options(7.5))
+                .unwrap()
+                .is_some()
+        );
+    }
+
+    #[test]
+    fn raycast_material_filter() {
+        let mut contree = create_contree(64, Vec3::new(-3., 0., 0.));
+        contree.insert(Vec3::new(-1., 0., 0.), 4).unwrap();
+        let (pos, dir) = (Vec3::new(5., 0., 0.), Vec3::new(-1., 0., 0.));
+
+        assert_eq!(contree.raycast(pos, dir).unwrap().unwrap().material, 4);
+
+        let skip_glass = |material| material != AIR && material != 4;
+        let options = RaycastOptions {
+            stops_on: Some(&skip_glass),
+            ..Default::default()
+        };
+        let hit = contree.raycast_with(pos, dir, &options).unwrap().unwrap();
+        assert_eq!(hit.material, 10);
+        assert_eq!(hit.voxel, IVec3::new(-3, 0, 0));
+    }
+
+    /// Nearest voxel the ray passes through, by testing it against every voxel in the tree
+    fn brute_force_raycast(contree: &Contree, pos: Vec3, dir: Vec3) -> Option<(IVec3, f32)> {
+        contree
+            .iter()
+            .filter_map(|(voxel, _)| {
+                let lo = voxel.as_vec3() - 0.5;
+                let t0 = (lo - pos) / dir;
+                let t1 = (lo + 1. - pos) / dir;
+                let t_near = t0.min(t1).max_element();
+                let t_far = t0.max(t1).min_element();
+                (t_near < t_far && t_far > 0.).then_some((voxel, t_near.max(0.) * dir.length()))
+            })
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+    }
+
+    #[test]
+    fn raycast_matches_brute_force() {
+        let mut state: u64 = 0x9e3779b97f4a7c15;
+        let mut next = move || {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (state >> 40) as f32 / (1 << 24) as f32
+        };
+
+        let mut contree = create_contree(64, Vec3::ZERO);
+        for _ in 0..300 {
+            let p = (Vec3::new(next(), next(), next()) * 40. - 20.).round();
+            contree.insert(p, 1).unwrap();
+        }
+
+        for i in 0..2000 {
+            // alternate between rays starting inside and outside the tree
+            let spread = if i % 2 == 0 { 30. } else { 80. };
+            let pos = (Vec3::new(next(), next(), next()) * 2. - 1.) * spread;
+            let dir = Vec3::new(next(), next(), next()) * 2. - 1.;
+
+            let hit = contree.raycast(pos, dir).unwrap();
+            let expected = brute_force_raycast(&contree, pos, dir);
+            assert_eq!(hit.map(|hit| hit.voxel), expected.map(|(voxel, _)| voxel));
+            if let (Some(hit), Some((_, distance))) = (hit, expected) {
+                assert!((hit.distance - distance).abs() < 1e-3);
+            }
+        }
+    }
+
+    #[test]
+    fn raycast_thin_diagonal() {
+        let mut contree = create_contree(64, Vec3::ZERO);
+        for i in 1..10 {
+            contree
+                .insert(Vec3::new(i as f32, i as f32, 0.), 2)
+                .unwrap();
+        }
+
+        // passes exactly through the corners shared by the voxels of the wall
+        for i in 1..9 {
+            let pos = Vec3::new(i as f32 - 2.5, i as f32 + 3.5, 0.);
+            let hit = contree
+                .raycast(pos, Vec3::new(1., -1., 0.))
+                .unwrap()
+                .unwrap();
+            assert_eq!(hit.voxel, IVec3::new(i + 1, i + 1, 0));
+            assert_eq!(hit.position, Vec3::new(i as f32 + 0.5, i as f32 + 0.5, 0.));
+        }
+        for i in 1..9 {
+            let pos = Vec3::new(i as f32 + 20.5, i as f32 - 20.5, 0.);
+            let hit = contree
+                .raycast(pos, Vec3::new(-1., 1., 0.))
+                .unwrap()
+                .unwrap();
+            assert_eq!(hit.voxel.x - hit.voxel.y, 0);
+        }
+    }
+
+    #[test]
+    fn raycast_many_matches_raycast() {
+        let mut contree = create_contree(64, Vec3::ZERO);
+        contree
+            .fill_aabb(IVec3::new(-20, -20, -20), IVec3::new(20, -18, 20), 3)
+            .unwrap();
+        contree
+            .fill_aabb(IVec3::new(5, -17, -3), IVec3::new(8, 4, 3), 4)
+            .unwrap();
+
+        let eye = Vec3::new(-10.5, 6.25, 0.75);
+        let mut rays: Vec<_> = (0..500)
+            .map(|i| {
+                let angle = i as f32 * 0.0125;
+                (
+                    eye,
+                    Vec3::new(angle.cos(), -0.4 - angle.sin(), angle.sin() * 0.5),
+                )
+            })
+            .collect();
+        rays.extend((0..100).map(|i| (Vec3::new(i as f32 - 50., 25., 1.), Vec3::NEG_Y)));
+        rays.push((Vec3::splat(100.), Vec3::ONE));
+
+        let hits = contree.raycast_many(&rays).unwrap();
+        assert_eq!(hits.len(), rays.len());
+        for (&(pos, dir), hit) in rays.iter().zip(&hits) {
+            assert_eq!(*hit, contree.raycast(pos, dir).unwrap());
+        }
+        assert!(hits.iter().filter(|hit| hit.is_some()).count() > 200);
+        assert!(hits.last().unwrap().is_none());
+    }
+
+    #[test]
+    fn raycast_solid_nodes() {
+        let mut contree = create_contree(64, Vec3::splat(-30.));
+        contree
+            .fill_aabb(IVec3::new(-16, -16, -16), IVec3::new(15, -1, 15), 1)
+            .unwrap();
+        contree
+            .fill_aabb(IVec3::new(-16, -32, -16), IVec3::new(15, -17, 15), 2)
+            .unwrap();
+        assert_ne!(contree.inners[contree.root.unwrap() as usize].solid, 0);
+
+        let hit = contree
+            .raycast(Vec3::new(3.1, 20., -7.3), Vec3::new(0.5, -1., 0.25))
+            .unwrap()
+            .unwrap();
+        assert_eq!(hit.material, 1);
+        assert_eq!(hit.normal, IVec3::Y);
+        assert_eq!(hit.voxel, IVec3::new(13, -1, -2));
+
+        // passing through the upper layer takes a single step per solid node
+        let below = |material| material == 2;
+        let options = RaycastOptions {
+            stops_on: Some(&below),
+            ..Default::default()
+        };
+        let hit = contree
+            .raycast_with(Vec3::new(0., 20., 0.), Vec3::NEG_Y, &options)
+            .unwrap()
+            .unwrap();
+        assert_eq!(hit.voxel, IVec3::new(0, -17, 0));
+        assert_eq!(hit.material, 2);
+        assert!(hit.steps < 8);
+    }
+
+    #[test]
+    fn raycast_errors() {
+        let mut contree = create_contree(64, Vec3::ZERO);
+
+        assert!(matches!(
+            contree.raycast(Vec3::INFINITY, Vec3::NEG_X),
+            Err(ContreeError::OutOfRange(_))
+        ));
+        assert_eq!(contree.raycast(Vec3::splat(5.), Vec3::NAN), Ok(None));
+
+        let root = contree.root.unwrap();
+        contree.inners[root as usize].children.fill(500);
+        assert!(matches!(
+            contree.raycast(Vec3::splat(5.), Vec3::NEG_ONE),
+            Err(ContreeError::CorruptedLink { parent, .. }) if parent == root
+        ));
+    }
 }
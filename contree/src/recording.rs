@@ -0,0 +1,252 @@
+use std::{
+    collections::HashSet,
+    sync::{Mutex, MutexGuard, PoisonError},
+};
+
+use bytemuck::{Pod, bytes_of, cast_slice};
+use glam::DVec3;
+
+use super::{Addr, Contree, ContreeInner, ContreeLeaf, GPUBindable, Material};
+
+/// Buffer contents as a GPU would hold them after every write so far
+#[derive(Debug, Default)]
+struct Shadow {
+    inners: Vec<u8>,
+    leaves: Vec<u8>,
+    materials: Vec<u8>,
+    bounds: Option<(Option<Addr>, u32, DVec3)>,
+}
+
+/// Binding that applies every write to byte arrays laid out like the GPU buffers
+///
+/// Only built for tests and with the `testing` feature. [`RecordingBinding::assert_mirrors`]
+/// checks that the uploads add up to the tree, catching writes that were missed or landed at
+/// the wrong offset.
+#[derive(Debug, Default)]
+pub struct RecordingBinding {
+    shadow: Mutex<Shadow>,
+}
+
+/// Copy data into a buffer at the offset of an address, growing it as a larger buffer would
+fn write_at<T: Pod>(buffer: &mut Vec<u8>, addr: Addr, data: &[T]) {
+    let bytes: &[u8] = cast_slice(data);
+    let offset = addr as usize * size_of::<T>();
+    if buffer.len() < offset + bytes.len() {
+        buffer.resize(offset + bytes.len(), 0);
+    }
+    buffer[offset..offset + bytes.len()].copy_from_slice(bytes);
+}
+
+/// Panic at the first live node whose uploaded bytes differ from the tree
+fn assert_nodes<T: Pod>(kind: &str, shadow: &[u8], nodes: impl Iterator<Item = T>, freed: &[Addr]) {
+    let freed: HashSet<Addr> = freed.iter().copied().collect();
+    for (addr, node) in nodes.enumerate() {
+        if freed.contains(&(addr as Addr)) {
+            continue;
+        }
+        let offset = addr * size_of::<T>();
+        let uploaded = shadow.get(offset..offset + size_of::<T>());
+        assert!(
+            uploaded == Some(bytes_of(&node)),
+            "{kind} node {addr} was {}",
+            if uploaded.is_some() {
+                "uploaded with stale data"
+            } else {
+                "never uploaded"
+            }
+        );
+    }
+}
+
+impl RecordingBinding {
+    fn shadow(&self) -> MutexGuard<'_, Shadow> {
+        self.shadow.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Bytes of the inner node buffer
+    pub fn inner_bytes(&self) -> Vec<u8> {
+        self.shadow().inners.clone()
+    }
+
+    /// Bytes of the leaf node buffer
+    pub fn leaf_bytes(&self) -> Vec<u8> {
+        self.shadow().leaves.clone()
+    }
+
+    /// Panic unless every live node, the palette and the bounds were uploaded as they are now
+    ///
+    /// Freed nodes are skipped, since nothing reads them until they are allocated again.
+    pub fn assert_mirrors(&self, contree: &Contree) {
+        let shadow = self.shadow();
+        assert_nodes(
+            "inner",
+            &shadow.inners,
+            contree.inners.iter().copied(),
+            &contree.inner_tombstones,
+        );
+        assert_nodes(
+            "leaf",
+            &shadow.leaves,
+            contree.leaves.iter().copied(),
+            &contree.leaf_tombstones,
+        );
+        assert!(
+            shadow.materials == cast_slice::<Material, u8>(contree.palette.materials()),
+            "palette was not uploaded"
+        );
+        assert_eq!(
+            shadow.bounds,
+            Some((contree.root, contree.size, contree.center_offset)),
+            "bounds were not uploaded"
+        );
+    }
+}
+
+impl GPUBindable for RecordingBinding {
+    fn write_inner(&self, addr: Addr, data: &[ContreeInner]) {
+        write_at(&mut self.shadow().inners, addr, data);
+    }
+
+    fn write_leaf(&self, addr: Addr, data: &[ContreeLeaf]) {
+        write_at(&mut self.shadow().leaves, addr, data);
+    }
+
+    fn write_materials(&self, data: &[Material]) {
+        let mut shadow = self.shadow();
+        shadow.materials.clear();
+        write_at(&mut shadow.materials, 0, data);
+    }
+
+    fn bounds_changed(&self, root: Option<Addr>, size: u32, center_offset: DVec3) {
+        self.shadow().bounds = Some((root, size, center_offset));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::{IVec3, Vec3};
+
+    use super::*;
+    use crate::BatchingBinding;
+
+    /// Apply one of every edit, checking the uploads after each
+    fn edit_all(contree: &mut Contree, check: impl Fn(&Contree)) {
+        check(contree);
+        contree.insert(Vec3::ZERO, 10).unwrap();
+        check(contree);
+        contree.insert(Vec3::new(40., -3., 7.), 2).unwrap();
+        check(contree);
+        contree.set(Vec3::ZERO, 3).unwrap();
+        check(contree);
+        contree.remove(Vec3::new(40., -3., 7.)).unwrap();
+        check(contree);
+        contree
+            .fill_aabb(IVec3::new(-9, -9, -9), IVec3::new(9, 0, 9), 4)
+            .unwrap();
+        check(contree);
+        contree
+            .fill_aabb(IVec3::new(-3, -3, -3), IVec3::new(3, 3, 3), 0)
+            .unwrap();
+        check(contree);
+        contree
+            .insert_many((0..300).map(|i| (Vec3::new(i as f32 - 150., 20., (i % 7) as f32), 5)))
+            .unwrap();
+        check(contree);
+        let lamp = contree
+            .add_material("lamp", Material::new([1.; 4], 0.).with_emission(2.))
+            .unwrap();
+        contree.insert(Vec3::new(5., 5., 5.), lamp).unwrap();
+        check(contree);
+        contree
+            .set_material(lamp, Material::new([0.5; 4], 0.))
+            .unwrap();
+        check(contree);
+
+        contree.begin_transaction();
+        contree
+            .fill_aabb(IVec3::new(-20, 10, -20), IVec3::new(20, 30, 20), 0)
+            .unwrap();
+        contree.commit_transaction();
+        check(contree);
+        contree.undo().unwrap();
+        check(contree);
+        contree.redo().unwrap();
+        check(contree);
+
+        contree.shrink_on_remove = true;
+        contree
+            .fill_aabb(IVec3::splat(-200), IVec3::splat(200), 0)
+            .unwrap();
+        contree.insert(Vec3::splat(3.), 1).unwrap();
+        check(contree);
+        contree.compact();
+        check(contree);
+    }
+
+    #[test]
+    fn every_edit_is_uploaded() {
+        let binding = RecordingBinding::default();
+        let mut contree = Contree::new(&binding).unwrap();
+        edit_all(&mut contree, |contree| binding.assert_mirrors(contree));
+    }
+
+    #[test]
+    fn batched_edits_are_uploaded() {
+        let binding = BatchingBinding::new(RecordingBinding::default());
+        let mut contree = Contree::new(&binding).unwrap();
+        edit_all(&mut contree, |contree| {
+            binding.flush();
+            binding.target.assert_mirrors(contree);
+        });
+    }
+
+    #[test]
+    fn load_is_uploaded() {
+        let mut contree = Contree::default();
+        contree.insert(Vec3::ZERO, 10).unwrap();
+        contree.insert(Vec3::splat(-70.), 2).unwrap();
+        let mut file = Vec::new();
+        contree.save(&mut file).unwrap();
+
+        let binding = RecordingBinding::default();
+        let loaded = Contree::load(file.as_slice(), &binding).unwrap();
+        binding.assert_mirrors(&loaded);
+    }
+
+    #[test]
+    #[should_panic(expected = "leaf node 0 was uploaded with stale data")]
+    fn detects_missed_write() {
+        let binding = RecordingBinding::default();
+        let mut contree = Contree::new(&binding).unwrap();
+        contree.insert(Vec3::ZERO, 10).unwrap();
+        contree.leaves[0].children[0] = 7;
+        binding.assert_mirrors(&contree);
+    }
+
+    /// Sends leaves to the inner buffer, as `ChannelBinding` once did
+    #[derive(Debug, Default)]
+    struct Misdirected(RecordingBinding);
+    impl GPUBindable for Misdirected {
+        fn write_inner(&self, addr: Addr, data: &[ContreeInner]) {
+            self.0.write_inner(addr, data);
+        }
+        fn write_leaf(&self, addr: Addr, data: &[ContreeLeaf]) {
+            write_at(&mut self.0.shadow().inners, addr, data);
+        }
+        fn write_materials(&self, data: &[Material]) {
+            self.0.write_materials(data);
+        }
+        fn bounds_changed(&self, root: Option<Addr>, size: u32, center_offset: DVec3) {
+            self.0.bounds_changed(root, size, center_offset);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "leaf node 0 was never uploaded")]
+    fn detects_misdirected_write() {
+        let binding = Misdirected::default();
+        let mut contree = Contree::new(&binding).unwrap();
+        contree.insert(Vec3::ZERO, 10).unwrap();
+        binding.0.assert_mirrors(&contree);
+    }
+}
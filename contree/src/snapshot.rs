@@ -0,0 +1,112 @@
+use std::{ops::Deref, sync::Arc};
+
+use super::{Contree, DummyBinding, journal::Journal};
+
+/// Read-only copy of a tree as it was when taken, see [`Contree::snapshot`]
+///
+/// Derefs to a [`Contree`] that cannot be edited, so everything taking `&self` such as
+/// [`Contree::find`] and [`Contree::raycast`] works on it.
+/// Cloning shares the same copy, and it can be sent to and shared between threads.
+#[derive(Debug, Clone)]
+pub struct ContreeSnapshot(Arc<Contree<'static>>);
+
+impl Deref for ContreeSnapshot {
+    type Target = Contree<'static>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl Contree<'_> {
+    /// Capture the tree for readers on other threads while this one keeps being edited
+    ///
+    /// Nodes are shared until the live tree writes to them, so taking a snapshot only costs a
+    /// pointer per chunk of nodes. The snapshot has no binding and an empty journal.
+    pub fn snapshot(&self) -> ContreeSnapshot {
+        ContreeSnapshot(Arc::new(Contree {
+            center_offset: self.center_offset,
+            root: self.root,
+            size: self.size,
+            inners: self.inners.clone(),
+            leaves: self.leaves.clone(),
+            inner_tombstones: self.inner_tombstones.clone(),
+            leaf_tombstones: self.leaf_tombstones.clone(),
+            shrink_on_remove: self.shrink_on_remove,
+            max_depth: self.max_depth,
+            palette: self.palette.clone(),
+            journal: Journal::default(),
+            binding: &DummyBinding,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::{IVec3, Vec3};
+
+    use crate::test_util::create_contree;
+
+    #[test]
+    fn snapshot_is_unaffected_by_edits() {
+        let mut contree = create_contree(64, Vec3::ZERO);
+        contree
+            .fill_aabb(IVec3::splat(-10), IVec3::splat(10), 3)
+            .unwrap();
+        let snapshot = contree.snapshot();
+
+        contree.remove(Vec3::ZERO).unwrap();
+        contree.insert(Vec3::splat(500.), 4).unwrap();
+        contree
+            .fill_aabb(IVec3::splat(-10), IVec3::new(10, 10, 0), 0)
+            .unwrap();
+
+        assert_eq!(snapshot.size, 64);
+        assert_eq!(snapshot.find(Vec3::ZERO).unwrap().material, Some(3));
+        assert_eq!(snapshot.find(Vec3::splat(-5.)).unwrap().material, Some(3));
+        assert!(snapshot.find(Vec3::splat(500.)).is_err());
+        assert_eq!(
+            snapshot
+                .raycast(Vec3::new(0., 0., -30.), Vec3::Z)
+                .unwrap()
+                .map(|hit| hit.voxel),
+            Some(IVec3::new(0, 0, -10))
+        );
+        assert_eq!(contree.find(Vec3::splat(-5.)).unwrap().material, None);
+    }
+
+    #[test]
+    fn snapshot_shares_nodes() {
+        let mut contree = create_contree(256, Vec3::ZERO);
+        contree
+            .insert_many((0..2000).map(|i| (Vec3::new(i as f32 % 100., 0., (i / 100) as f32), 2)))
+            .unwrap();
+        let snapshot = contree.snapshot();
+        assert_eq!(contree.leaves.unshared_chunks(), 0);
+
+        contree.insert(Vec3::new(0., 1., 0.), 5).unwrap();
+        assert!(contree.leaves.unshared_chunks() <= 1);
+        assert_eq!(snapshot.find(Vec3::new(0., 1., 0.)).unwrap().material, None);
+    }
+
+    #[test]
+    fn snapshot_read_from_threads() {
+        let mut contree = create_contree(64, Vec3::ZERO);
+        let snapshot = contree.snapshot();
+
+        std::thread::scope(|scope| {
+            let readers: Vec<_> = (0..4)
+                .map(|_| {
+                    let snapshot = snapshot.clone();
+                    scope.spawn(move || snapshot.find(Vec3::ZERO).unwrap().material)
+                })
+                .collect();
+            for i in 1..20 {
+                contree.insert(Vec3::ZERO, i).unwrap();
+            }
+            for reader in readers {
+                assert_eq!(reader.join().unwrap(), Some(10));
+            }
+        });
+    }
+}
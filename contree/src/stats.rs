@@ -0,0 +1,251 @@
+use std::fmt;
+
+use super::{
+    Addr, Contree, ContreeInner, ContreeLeaf, node_management::Child, palette::MAX_MATERIALS,
+};
+
+/// Nodes at one depth of a tree and how full they are
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DepthStats {
+    pub nodes: usize,
+    /// Occupied child slots, or voxels for leaves
+    pub children: usize,
+}
+
+impl DepthStats {
+    /// Fraction of the child slots that are occupied
+    pub fn occupancy(&self) -> f32 {
+        if self.nodes == 0 {
+            0.
+        } else {
+            self.children as f32 / (self.nodes * 64) as f32
+        }
+    }
+}
+
+/// Size and memory use of a tree, see [`Contree::stats`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Stats {
+    /// Inner nodes reachable from the root
+    pub inners: usize,
+    /// Leaf nodes reachable from the root
+    pub leaves: usize,
+    /// Freed inner nodes waiting to be reused
+    pub inner_tombstones: usize,
+    /// Freed leaf nodes waiting to be reused
+    pub leaf_tombstones: usize,
+    /// Children filled with one material, standing in for a whole subtree
+    pub solid: usize,
+    /// Occupied voxels, counting every voxel inside solid children
+    pub voxels: u128,
+    /// Occupied voxels of the leaf nodes
+    pub leaf_voxels: usize,
+    /// Voxels of each material, indexed by material
+    pub materials: Vec<u128>,
+    /// Nodes at each depth, starting with the root
+    pub depths: Vec<DepthStats>,
+    /// Bytes held by the node arenas and their tombstones
+    pub cpu_bytes: usize,
+    /// Bytes the node arenas take up once uploaded
+    pub gpu_bytes: usize,
+    /// Bytes held by the undo history
+    pub journal_bytes: usize,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self {
+            inners: 0,
+            leaves: 0,
+            inner_tombstones: 0,
+            leaf_tombstones: 0,
+            solid: 0,
+            voxels: 0,
+            leaf_voxels: 0,
+            materials: vec![0; MAX_MATERIALS],
+            depths: Vec::new(),
+            cpu_bytes: 0,
+            gpu_bytes: 0,
+            journal_bytes: 0,
+        }
+    }
+}
+
+impl Stats {
+    /// Average fraction of the voxels of a leaf that are occupied
+    pub fn leaf_fill(&self) -> f32 {
+        DepthStats {
+            nodes: self.leaves,
+            children: self.leaf_voxels,
+        }
+        .occupancy()
+    }
+
+    /// Percentage of the uploaded bytes taken up by freed nodes
+    pub fn fragmentation(&self) -> f32 {
+        let freed = self.inner_tombstones * size_of::<ContreeInner>()
+            + self.leaf_tombstones * size_of::<ContreeLeaf>();
+        if self.gpu_bytes == 0 {
+            0.
+        } else {
+            freed as f32 / self.gpu_bytes as f32 * 100.
+        }
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mib = |bytes: usize| bytes as f64 / (1024. * 1024.);
+        write!(
+            f,
+            "{} voxels in {} inner and {} leaf nodes, {:.1} MiB CPU, {:.1} MiB GPU, {:.1}% fragmented",
+            self.voxels,
+            self.inners,
+            self.leaves,
+            mib(self.cpu_bytes + self.journal_bytes),
+            mib(self.gpu_bytes),
+            self.fragmentation()
+        )
+    }
+}
+
+impl Contree<'_> {
+    /// Count the nodes and voxels of the tree and the memory they take up
+    ///
+    /// Walks every node reachable from the root, links that [`Contree::validate`] would
+    /// reject are skipped.
+    pub fn stats(&self) -> Stats {
+        let node_bytes = self.inners.len() * size_of::<ContreeInner>()
+            + self.leaves.len() * size_of::<ContreeLeaf>();
+        let mut stats = Stats {
+            inner_tombstones: self.inner_tombstones.len(),
+            leaf_tombstones: self.leaf_tombstones.len(),
+            cpu_bytes: node_bytes
+                + (self.inner_tombstones.len() + self.leaf_tombstones.len()) * size_of::<Addr>(),
+            gpu_bytes: node_bytes,
+            journal_bytes: self.journal.bytes(),
+            ..Default::default()
+        };
+
+        let mut stack: Vec<(Addr, usize, u32)> = self
+            .root
+            .into_iter()
+            .map(|root| (root, 0, self.size / 4))
+            .collect();
+        while let Some((addr, depth, child_size)) = stack.pop() {
+            let Some(node) = self.inners.get(addr as usize) else {
+                continue;
+            };
+            if stats.depths.len() <= depth {
+                stats.depths.resize(depth + 1, DepthStats::default());
+            }
+            stats.inners += 1;
+            stats.depths[depth].nodes += 1;
+            stats.depths[depth].children += node.contains.count_ones() as usize;
+
+            for index in 0..64 {
+                match node.child(index) {
+                    Child::Empty => {}
+                    Child::Solid(material) => {
+                        let voxels = (child_size as u128).pow(3);
+                        stats.solid += 1;
+                        stats.voxels += voxels;
+                        stats.materials[material as usize] += voxels;
+                    }
+                    Child::Inner(child) if child_size > 4 => {
+                        stack.push((child, depth + 1, child_size / 4))
+                    }
+                    Child::Inner(_) => {}
+                    Child::Leaf(child) => {
+                        let Some(leaf) = self.leaves.get(child as usize) else {
+                            continue;
+                        };
+                        if stats.depths.len() <= depth + 1 {
+                            stats.depths.resize(depth + 2, DepthStats::default());
+                        }
+                        stats.leaves += 1;
+                        stats.depths[depth + 1].nodes += 1;
+                        stats.depths[depth + 1].children += leaf.contains.count_ones() as usize;
+                        stats.voxels += leaf.contains.count_ones() as u128;
+                        stats.leaf_voxels += leaf.contains.count_ones() as usize;
+                        for i in (0..64).filter(|&i| (leaf.contains >> i) & 1 == 1) {
+                            stats.materials[leaf.children[i] as usize] += 1;
+                        }
+                    }
+                }
+            }
+        }
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::{IVec3, Vec3};
+
+    use crate::Contree;
+    use crate::test_util::create_contree;
+
+    #[test]
+    fn stats_count_nodes_and_voxels() {
+        let mut contree = create_contree(256, Vec3::ZERO);
+        contree.insert(Vec3::ONE, 10).unwrap();
+        contree.insert(Vec3::splat(-70.), 2).unwrap();
+        contree
+            .fill_aabb(IVec3::new(16, 16, 16), IVec3::new(31, 31, 31), 3)
+            .unwrap();
+        contree.remove(Vec3::splat(-70.)).unwrap();
+
+        let stats = contree.stats();
+        assert_eq!(stats.inners, 3);
+        assert_eq!(stats.leaves, 1);
+        assert_eq!(stats.inner_tombstones, 2);
+        assert_eq!(stats.leaf_tombstones, 1);
+        assert_eq!(stats.solid, 1);
+        assert_eq!(stats.voxels, 2 + 16 * 16 * 16);
+        assert_eq!(stats.materials[10], 2);
+        assert_eq!(stats.materials[3], 16 * 16 * 16);
+        assert_eq!(stats.materials.iter().sum::<u128>(), stats.voxels);
+
+        let children: Vec<_> = stats.depths.iter().map(|depth| depth.children).collect();
+        assert_eq!(children, &[1, 2, 1, 2]);
+        assert_eq!(stats.leaf_fill(), 2. / 64.);
+
+        assert_eq!(stats.gpu_bytes, 5 * 296 + 2 * 80);
+        assert_eq!(stats.cpu_bytes, stats.gpu_bytes + 3 * 4);
+        assert_eq!(
+            stats.fragmentation(),
+            (2. * 296. + 80.) / (5. * 296. + 2. * 80.) * 100.
+        );
+    }
+
+    #[test]
+    fn leaf_fill_without_leaves() {
+        let mut contree = Contree {
+            size: 64,
+            ..Default::default()
+        };
+        contree.fill_aabb(IVec3::ZERO, IVec3::splat(15), 3).unwrap();
+
+        let stats = contree.stats();
+        assert_eq!(stats.leaves, 0);
+        assert_eq!(stats.solid, 1);
+        assert_eq!(stats.leaf_fill(), 0.);
+    }
+
+    #[test]
+    fn stats_match_validate() {
+        let mut contree = create_contree(64, Vec3::ZERO);
+        contree
+            .insert_many((0..200).map(|i| (Vec3::new(i as f32 - 100., 0., (i % 9) as f32), 4)))
+            .unwrap();
+        assert_eq!(contree.validate(), Ok(contree.stats()));
+
+        contree.compact();
+        let stats = contree.stats();
+        assert_eq!(stats.fragmentation(), 0.);
+        assert_eq!(stats.inners, contree.inners.len());
+        assert_eq!(stats.leaves, contree.leaves.len());
+        assert!(stats.to_string().contains("0.0% fragmented"));
+    }
+}
@@ -0,0 +1,96 @@
+use std::sync::Mutex;
+
+use glam::{DVec3, Vec3};
+
+use super::{
+    Addr, ChildIndex, Contree, ContreeError, ContreeInner, ContreeLeaf, GPUBindable, Material,
+};
+
+/// Tree of the given size holding a single voxel of material 10
+pub fn create_contree(size: u32, p: Vec3) -> Contree<'static> {
+    assert!(size > 4, "The root node cannot be a leaf!");
+    let mut contree = Contree {
+        size,
+        ..Default::default()
+    };
+    contree.insert(p, 10).unwrap();
+    contree
+}
+
+/// Point the first child of the root past the end of its arena, returning the error edits
+/// through it report
+pub fn corrupt_root_link(contree: &mut Contree) -> ContreeError {
+    let root = contree.root.unwrap();
+    let index = contree.inners[root as usize].contains.trailing_zeros() as ChildIndex;
+    contree.inners[root as usize].children[index as usize] = 1000;
+    ContreeError::CorruptedLink {
+        parent: root,
+        index,
+    }
+}
+
+/// Binding that logs every call it receives
+#[derive(Debug, Default)]
+pub struct WriteLog {
+    pub inners: Mutex<Vec<(Addr, Vec<ContreeInner>)>>,
+    pub leaves: Mutex<Vec<(Addr, Vec<ContreeLeaf>)>>,
+    pub materials: Mutex<usize>,
+    pub freed_inners: Mutex<Vec<Addr>>,
+    pub freed_leaves: Mutex<Vec<Addr>>,
+    pub bounds: Mutex<Vec<(Option<Addr>, u32, DVec3)>>,
+}
+
+impl WriteLog {
+    /// Start and length of every inner node write so far
+    pub fn inner_runs(&self) -> Vec<(Addr, usize)> {
+        let inners = self.inners.lock().unwrap();
+        inners
+            .iter()
+            .map(|(addr, data)| (*addr, data.len()))
+            .collect()
+    }
+
+    /// Start and length of every leaf node write so far
+    pub fn leaf_runs(&self) -> Vec<(Addr, usize)> {
+        let leaves = self.leaves.lock().unwrap();
+        leaves
+            .iter()
+            .map(|(addr, data)| (*addr, data.len()))
+            .collect()
+    }
+
+    /// Forget the node writes so far
+    pub fn clear(&self) {
+        self.inners.lock().unwrap().clear();
+        self.leaves.lock().unwrap().clear();
+    }
+}
+
+impl GPUBindable for WriteLog {
+    fn write_inner(&self, addr: Addr, data: &[ContreeInner]) {
+        self.inners.lock().unwrap().push((addr, data.to_vec()));
+    }
+
+    fn write_leaf(&self, addr: Addr, data: &[ContreeLeaf]) {
+        self.leaves.lock().unwrap().push((addr, data.to_vec()));
+    }
+
+    fn write_materials(&self, _: &[Material]) {
+        *self.materials.lock().unwrap() += 1;
+    }
+
+    fn free_inner(&self, addr: Addr) {
+        self.freed_inners.lock().unwrap().push(addr);
+    }
+
+    fn free_leaf(&self, addr: Addr) {
+        self.freed_leaves.lock().unwrap().push(addr);
+    }
+
+    fn bounds_changed(&self, root: Option<Addr>, size: u32, center_offset: DVec3) {
+        self.bounds
+            .lock()
+            .unwrap()
+            .push((root, size, center_offset));
+    }
+}
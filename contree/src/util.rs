@@ -1,10 +1,17 @@
-use glam::{U64Vec3, UVec3, Vec3};
+use glam::{DVec3, IVec3, U64Vec3, UVec3, Vec3};
 
-use crate::ChildIndex;
+use crate::{ChildIndex, ContreeError};
 
 use super::Contree;
 
-pub fn morton_code(norm_p: UVec3) -> u64 {
+/// Interleaved bits of a normalized position, with x in the highest bit of each triple
+pub type MortonCode = u128;
+
+/// Bits of each axis held by one half of a [`MortonCode`]
+const HALF_BITS: u32 = 21;
+
+/// Interleave the lowest 21 bits of each axis
+fn spread(norm_p: UVec3) -> u64 {
     let mut res = (norm_p & UVec3::splat(0x1fffff)).as_u64vec3();
     res = (res | res << 32) & U64Vec3::splat(0x1f00000000ffff);
     res = (res | res << 16) & U64Vec3::splat(0x1f0000ff0000ff);
@@ -15,22 +22,126 @@ pub fn morton_code(norm_p: UVec3) -> u64 {
     (res.x << 2) | (res.y << 1) | res.z
 }
 
-pub const MAX_MORTON_INDEX: u8 = 8;
-pub fn morton_index(code: u64, index: u8) -> Option<ChildIndex> {
+/// Inverse of [`spread`]
+fn compact(code: u64) -> UVec3 {
+    let mut res = U64Vec3::new(code >> 2, code >> 1, code) & U64Vec3::splat(0x1249249249249249);
+    res = (res | res >> 2) & U64Vec3::splat(0x10c30c30c30c30c3);
+    res = (res | res >> 4) & U64Vec3::splat(0x100f00f00f00f00f);
+    res = (res | res >> 8) & U64Vec3::splat(0x1f0000ff0000ff);
+    res = (res | res >> 16) & U64Vec3::splat(0x1f00000000ffff);
+    res = (res | res >> 32) & U64Vec3::splat(0x1fffff);
+
+    res.as_uvec3()
+}
+
+/// Every bit of the position is kept, the code has room for 42 bits on each axis
+pub fn morton_code(norm_p: UVec3) -> MortonCode {
+    let low = spread(norm_p);
+    let high = spread(norm_p >> HALF_BITS);
+    ((high as MortonCode) << (3 * HALF_BITS)) | low as MortonCode
+}
+
+/// Inverse of [`morton_code`]
+pub fn morton_decode(code: MortonCode) -> UVec3 {
+    let low = compact(code as u64 & ((1 << (3 * HALF_BITS)) - 1));
+    let high = compact((code >> (3 * HALF_BITS)) as u64);
+    (high << HALF_BITS) | low
+}
+
+/// Morton index of the voxels within a leaf, the root's children are at the lowest index
+pub const MAX_MORTON_INDEX: u8 = 12;
+/// Size of the largest tree whose voxels can be addressed with [`morton_index`]
+///
+/// Capped at 2^24 since positions are `f32`, which cannot tell whole voxels apart beyond it.
+pub const MAX_SIZE: u32 = 1 << (2 * MAX_MORTON_INDEX);
+/// Furthest a position may be from the origin on any axis, past it an `f32` skips voxels
+pub const MAX_COORDINATE: f32 = (1 << 24) as f32;
+
+/// Whether a position is finite and close enough to the origin to address a single voxel
+pub fn is_addressable(p: Vec3) -> bool {
+    p.is_finite() && p.abs().max_element() <= MAX_COORDINATE
+}
+pub fn morton_index(code: MortonCode, index: u8) -> Option<ChildIndex> {
     if index > MAX_MORTON_INDEX {
         None
     } else {
-        Some(((code >> (48 - 6 * index)) & 0b111111) as ChildIndex)
+        Some(((code >> (6 * (MAX_MORTON_INDEX - index) as u32)) & 0b111111) as ChildIndex)
     }
 }
 
+/// Position of a child within its parent, each axis in the range 0..4
+pub fn child_offset(index: ChildIndex) -> UVec3 {
+    let index = index as u32;
+    UVec3::new(
+        ((index >> 4) & 0b10) | ((index >> 2) & 0b1),
+        ((index >> 3) & 0b10) | ((index >> 1) & 0b1),
+        ((index >> 2) & 0b10) | (index & 0b1),
+    )
+}
+
 impl Contree<'_> {
+    /// Morton index of the root node's children
+    pub(crate) fn first_morton_index(&self) -> u8 {
+        MAX_MORTON_INDEX + 1 - (self.size.ilog2() as u8 / 2)
+    }
+
+    /// Check that the size is one the tree can be traversed at
+    pub fn check_size(size: u32) -> Result<(), ContreeError> {
+        if (16..=MAX_SIZE).contains(&size)
+            && size.is_power_of_two()
+            && size.ilog2().is_multiple_of(2)
+        {
+            Ok(())
+        } else {
+            Err(ContreeError::InvalidSize(size))
+        }
+    }
+
+    /// Largest size the tree grows to, from [`Contree::max_depth`] capped at [`MAX_SIZE`]
+    pub fn max_size(&self) -> u32 {
+        4_u32.pow(self.max_depth.clamp(2, MAX_MORTON_INDEX) as u32)
+    }
+
+    /// Morton code of a position inside the tree
+    pub(crate) fn locate(&self, p: Vec3) -> Result<MortonCode, ContreeError> {
+        Self::check_size(self.size)?;
+        if !self.in_bounds(p) {
+            return Err(ContreeError::OutOfRange(p));
+        }
+        Ok(morton_code(self.normalize(p)))
+    }
+
+    /// Continuous tree coordinates of a point, voxel `n` covers `n..n + 1` on every axis
+    ///
+    /// Computed in f64, since above 2^24 an f32 cannot tell neighbouring voxels apart
+    pub(crate) fn tree_coords(&self, p: DVec3) -> DVec3 {
+        p - self.center_offset + (self.size as f64 + 1.) / 2.
+    }
+
+    /// Inverse of [`Contree::tree_coords`]
+    pub(crate) fn world_coords(&self, norm_p: DVec3) -> DVec3 {
+        norm_p - (self.size as f64 + 1.) / 2. + self.center_offset
+    }
+
     pub fn normalize(&self, p: Vec3) -> UVec3 {
-        (p - self.center_offset + ((self.size + 1) as f32 / 2.)).as_uvec3()
+        self.tree_coords(p.as_dvec3()).as_uvec3()
+    }
+
+    /// Tree coordinates of a voxel, which are outside `0..size` when it is out of bounds
+    pub(crate) fn normalize_voxel(&self, p: IVec3) -> IVec3 {
+        self.tree_coords(p.as_dvec3()).floor().as_ivec3()
+    }
+
+    /// Inverse of [`Contree::normalize_voxel`]
+    pub(crate) fn denormalize_voxel(&self, norm_p: UVec3) -> IVec3 {
+        self.world_coords(norm_p.as_dvec3()).ceil().as_ivec3()
     }
 
     pub fn in_bounds(&self, p: Vec3) -> bool {
-        let res = (p - self.center_offset)
+        if !is_addressable(p) {
+            return false;
+        }
+        let res = (p.as_dvec3() - self.center_offset)
             .as_ivec3()
             .map(|v| if v < 0 { -v - 1 } else { v });
         let cutoff = self.size / 2;
@@ -49,6 +160,63 @@ mod tests {
         assert_eq!(code, 0b011100000101);
     }
 
+    #[test]
+    fn morton_decode_roundtrip() {
+        for p in [
+            UVec3::ZERO,
+            UVec3::new(5, 8, 9),
+            UVec3::new(0x1fffff, 0, 0x15555),
+            UVec3::splat(0x1fffff),
+            UVec3::new(0x200000, 0xfedcba9, 12),
+            UVec3::splat(u32::MAX),
+        ] {
+            assert_eq!(morton_decode(morton_code(p)), p);
+        }
+    }
+
+    #[test]
+    fn denormalize_roundtrip() {
+        let mut contree = Contree {
+            size: 64,
+            ..Default::default()
+        };
+        for center_offset in [DVec3::ZERO, DVec3::new(16., -48., 8.), DVec3::splat(0.25)] {
+            contree.center_offset = center_offset;
+            for p in [IVec3::ZERO, IVec3::new(-3, 7, 12), IVec3::splat(-20)] {
+                let p = p + center_offset.as_ivec3();
+                let norm_p = contree.normalize_voxel(p);
+                assert_eq!(contree.denormalize_voxel(norm_p.as_uvec3()), p);
+            }
+        }
+    }
+
+    #[test]
+    fn morton_code_high_bits() {
+        let p = UVec3::new(1 << 23, 0, 1 << 17);
+        let code = morton_code(p);
+        assert_eq!(code, (1 << (23 * 3 + 2)) | (1 << (17 * 3)));
+
+        // the root of the largest tree splits on the highest bit of every axis
+        let first = MAX_MORTON_INDEX + 1 - (MAX_SIZE.ilog2() as u8 / 2);
+        assert_eq!(first, 1);
+        assert_eq!(morton_index(code, first), Some(0b100000));
+        assert_eq!(morton_index(code, MAX_MORTON_INDEX - 8), Some(0b001000));
+        assert_eq!(morton_index(code, MAX_MORTON_INDEX), Some(0));
+    }
+
+    #[test]
+    fn check_size() {
+        for size in [16, 64, MAX_SIZE] {
+            assert_eq!(Contree::check_size(size), Ok(()));
+        }
+        for size in [0, 4, 32, 100, MAX_SIZE * 4] {
+            assert_eq!(
+                Contree::check_size(size),
+                Err(ContreeError::InvalidSize(size))
+            );
+        }
+    }
+
     #[test]
     fn morton_code_zero() {
         let code = morton_code(UVec3::new(0, 0, 0));
@@ -64,6 +232,15 @@ mod tests {
         assert_eq!(traversal_iter.collect::<Vec<_>>(), &[0, 0, 0]);
     }
 
+    #[test]
+    fn child_offset_matches_morton() {
+        for p in (0..4).flat_map(|x| (0..4).flat_map(move |y| (0..4).map(move |z| (x, y, z)))) {
+            let p = UVec3::from(p);
+            let index = morton_index(morton_code(p), MAX_MORTON_INDEX).unwrap();
+            assert_eq!(child_offset(index), p);
+        }
+    }
+
     #[test]
     fn contains_skews_negative() {
         let contree = Contree::default();
@@ -72,5 +249,6 @@ mod tests {
         assert!(contree.in_bounds(Vec3::splat(7.5)));
         assert!(!contree.in_bounds(Vec3::splat(8.5)));
         assert!(!contree.in_bounds(Vec3::splat(-9.)));
+        assert!(!contree.in_bounds(Vec3::NAN));
     }
 }
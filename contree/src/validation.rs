@@ -0,0 +1,252 @@
+use super::{Addr, ChildIndex, Contree, node_management::Child, stats::Stats};
+
+/// Broken invariant found by [`Contree::validate`]
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum Violation {
+    #[error("tree size {0} is not a power of 4 the tree can be traversed at")]
+    InvalidSize(u32),
+    #[error("root {0} is not a live inner node")]
+    InvalidRoot(Addr),
+    #[error("bitmasks of child {index} of node {parent} contradict each other")]
+    Bits { parent: Addr, index: ChildIndex },
+    #[error("child {index} of node {parent} points past the end of its arena")]
+    OutOfBounds { parent: Addr, index: ChildIndex },
+    #[error("child {index} of node {parent} points at a freed node")]
+    Tombstoned { parent: Addr, index: ChildIndex },
+    #[error("child {index} of node {parent} points at a node that was already reached")]
+    Shared { parent: Addr, index: ChildIndex },
+    #[error("child {index} of node {parent} is the wrong kind of node for its depth")]
+    WrongKind { parent: Addr, index: ChildIndex },
+    #[error("inner node {0} is neither freed nor reachable from the root")]
+    UnreachableInner(Addr),
+    #[error("leaf node {0} is neither freed nor reachable from the root")]
+    UnreachableLeaf(Addr),
+}
+
+/// Nodes of one arena reached so far, and the ones that were freed
+struct Marks {
+    reached: Vec<bool>,
+    freed: Vec<bool>,
+}
+
+impl Marks {
+    fn new(len: usize, tombstones: &[Addr]) -> Self {
+        let mut freed = vec![false; len];
+        for &addr in tombstones {
+            if let Some(freed) = freed.get_mut(addr as usize) {
+                *freed = true;
+            }
+        }
+        Self {
+            reached: vec![false; len],
+            freed,
+        }
+    }
+
+    /// Mark a child as reached, or describe why it cannot be
+    fn reach(&mut self, addr: Addr, parent: Addr, index: ChildIndex) -> Result<(), Violation> {
+        let Some(reached) = self.reached.get_mut(addr as usize) else {
+            return Err(Violation::OutOfBounds { parent, index });
+        };
+        if self.freed[addr as usize] {
+            return Err(Violation::Tombstoned { parent, index });
+        }
+        if std::mem::replace(reached, true) {
+            return Err(Violation::Shared { parent, index });
+        }
+        Ok(())
+    }
+
+    /// Live nodes that were never reached
+    fn unreached(&self) -> impl Iterator<Item = Addr> + '_ {
+        (0..self.reached.len())
+            .filter(|&addr| !self.reached[addr] && !self.freed[addr])
+            .map(|addr| addr as Addr)
+    }
+}
+
+impl Contree<'_> {
+    /// Check every structural invariant of the tree, reporting all that are broken
+    ///
+    /// Walks every node reachable from the root, and counts a valid tree with [`Contree::stats`].
+    pub fn validate(&self) -> Result<Stats, Vec<Violation>> {
+        if Self::check_size(self.size).is_err() {
+            return Err(vec![Violation::InvalidSize(self.size)]);
+        }
+
+        let mut violations = Vec::new();
+        let mut inners = Marks::new(self.inners.len(), &self.inner_tombstones);
+        let mut leaves = Marks::new(self.leaves.len(), &self.leaf_tombstones);
+
+        let mut stack = Vec::new();
+        if let Some(root) = self.root {
+            if (root as usize) < self.inners.len() && !inners.freed[root as usize] {
+                inners.reached[root as usize] = true;
+                stack.push((root, self.size / 4));
+            } else {
+                violations.push(Violation::InvalidRoot(root));
+            }
+        }
+
+        while let Some((addr, child_size)) = stack.pop() {
+            let node = self.inners[addr as usize];
+            // flags of missing children, and children flagged as both leaf and solid
+            let contradictions =
+                ((node.leaf | node.solid | node.light) & !node.contains) | (node.leaf & node.solid);
+            for index in 0..64 {
+                let parent = addr;
+                if (contradictions >> index) & 1 == 1 {
+                    violations.push(Violation::Bits { parent, index });
+                    continue;
+                }
+
+                let reached = match node.child(index) {
+                    Child::Empty | Child::Solid(_) => Ok(()),
+                    // leaves hold voxels, so they sit exactly one level above them
+                    Child::Inner(_) if child_size == 4 => {
+                        Err(Violation::WrongKind { parent, index })
+                    }
+                    Child::Leaf(_) if child_size != 4 => {
+                        Err(Violation::WrongKind { parent, index })
+                    }
+                    Child::Inner(child) => inners.reach(child, parent, index).map(|()| {
+                        stack.push((child, child_size / 4));
+                    }),
+                    Child::Leaf(child) => leaves.reach(child, parent, index),
+                };
+                violations.extend(reached.err());
+            }
+        }
+
+        violations.extend(inners.unreached().map(Violation::UnreachableInner));
+        violations.extend(leaves.unreached().map(Violation::UnreachableLeaf));
+        if violations.is_empty() {
+            Ok(self.stats())
+        } else {
+            Err(violations)
+        }
+    }
+
+    /// Panic if an edit broke the tree, only when built with the `validate` feature
+    pub(crate) fn check_invariants(&self) {
+        #[cfg(feature = "validate")]
+        if let Err(violations) = self.validate() {
+            panic!("edit broke the tree: {violations:#?}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::{IVec3, Vec3};
+
+    use super::*;
+    use crate::test_util::create_contree;
+
+    /// Child slot on the path to a position, below the root
+    fn slot(contree: &Contree, p: Vec3) -> (Addr, ChildIndex) {
+        let found = contree.find_at_depth(p, 1).unwrap();
+        let index = (0..64)
+            .find(|&i| {
+                contree.inners[contree.root.unwrap() as usize].child(i)
+                    == Child::Inner(found.parent_address)
+            })
+            .unwrap();
+        (contree.root.unwrap(), index)
+    }
+
+    #[test]
+    fn validate_counts_nodes() {
+        let mut contree = create_contree(256, Vec3::ZERO);
+        contree.insert(Vec3::splat(-70.), 2).unwrap();
+        contree
+            .fill_aabb(IVec3::new(16, 16, 16), IVec3::new(31, 31, 31), 3)
+            .unwrap();
+        contree.remove(Vec3::splat(-70.)).unwrap();
+
+        let stats = contree.validate().unwrap();
+        assert_eq!(
+            (stats.inners, stats.leaves, stats.solid, stats.voxels),
+            (3, 1, 1, 1 + 16 * 16 * 16)
+        );
+        assert_eq!(
+            Contree::default().validate().map(|stats| stats.inners),
+            Ok(1)
+        );
+    }
+
+    #[test]
+    fn validate_reports_broken_links() {
+        let mut contree = create_contree(256, Vec3::ZERO);
+        contree.insert(Vec3::splat(-70.), 2).unwrap();
+        let (parent, index) = slot(&contree, Vec3::ZERO);
+        let (other_parent, other_index) = slot(&contree, Vec3::splat(-70.));
+        let node = contree.inners[parent as usize].children[index as usize];
+
+        contree.inners[parent as usize].children[index as usize] = 1000;
+        assert_eq!(
+            contree.validate().unwrap_err()[0],
+            Violation::OutOfBounds { parent, index }
+        );
+
+        contree.inner_tombstones.push(node);
+        contree.inners[parent as usize].children[index as usize] = node;
+        assert_eq!(
+            contree.validate().unwrap_err()[0],
+            Violation::Tombstoned { parent, index }
+        );
+        contree.inner_tombstones.clear();
+
+        // both slots point at the same node, leaving the other one unreachable
+        let other = contree.inners[other_parent as usize].children[other_index as usize];
+        contree.inners[other_parent as usize].children[other_index as usize] = node;
+        let violations = contree.validate().unwrap_err();
+        assert!(violations.contains(&Violation::UnreachableInner(other)));
+        assert!(
+            violations
+                .iter()
+                .any(|v| matches!(v, Violation::Shared { .. }))
+        );
+    }
+
+    #[test]
+    fn validate_reports_bad_bits() {
+        let mut contree = create_contree(64, Vec3::ZERO);
+        let root = contree.root.unwrap();
+        let (parent, index) = slot(&contree, Vec3::ZERO);
+
+        contree.inners[root as usize].leaf |= 1 << 5;
+        assert_eq!(
+            contree.validate(),
+            Err(vec![Violation::Bits {
+                parent: root,
+                index: 5
+            }])
+        );
+        contree.inners[root as usize].leaf &= !(1 << 5);
+
+        // a leaf where inner nodes belong, cutting off everything below it
+        contree.inners[parent as usize].leaf |= 1 << index;
+        assert_eq!(
+            contree.validate(),
+            Err(vec![
+                Violation::WrongKind { parent, index },
+                Violation::UnreachableInner(1),
+                Violation::UnreachableLeaf(0)
+            ])
+        );
+    }
+
+    #[test]
+    fn validate_reports_bad_bounds() {
+        let mut contree = create_contree(64, Vec3::ZERO);
+        contree.size = 32;
+        assert_eq!(contree.validate(), Err(vec![Violation::InvalidSize(32)]));
+
+        contree.size = 64;
+        contree.root = Some(10);
+        let violations = contree.validate().unwrap_err();
+        assert_eq!(violations[0], Violation::InvalidRoot(10));
+        assert!(violations.contains(&Violation::UnreachableInner(0)));
+    }
+}
@@ -0,0 +1,274 @@
+//! On-disk format for saving and loading a [`Contree`]
+//!
+//! | Field            | Encoding                                                    |
+//! |------------------|-------------------------------------------------------------|
+//! | magic            | the 4 bytes [`MAGIC`]                                       |
+//! | version          | `u32`, little endian, [`FORMAT_VERSION`]                    |
+//! | size             | `u32`                                                       |
+//! | max_depth        | `u8`                                                        |
+//! | center_offset    | 3 `f64`s                                                    |
+//! | root             | `Option<Addr>`                                              |
+//! | inners           | `u32` length followed by the nodes                          |
+//! | leaves           | `u32` length followed by the nodes                          |
+//! | palette          | [`Palette`] names then materials, each after a `u32` length |
+//! | journal          | undo and redo stacks, see [`Journal`]                       |
+//!
+//! Everything after the version is encoded with `serde-binary` in little endian.
+//! Arenas are written compacted, so tombstones are never stored and the root is at address 0.
+//! The version is bumped whenever the layout changes, and files of any other version are rejected.
+
+use std::io::{Read, Write};
+
+use glam::DVec3;
+use serde::{Deserialize, Serialize};
+use serde_binary::binary_stream::Endian;
+
+use super::{
+    Addr, Contree, ContreeError, ContreeInner, ContreeLeaf, GPUBindable, compaction::CompactArenas,
+    journal::Journal, palette::Palette,
+};
+
+/// Bytes every world file starts with
+pub const MAGIC: [u8; 4] = *b"VXCT";
+/// Version of the format written by [`Contree::save`], the only one [`Contree::load`] accepts
+pub const FORMAT_VERSION: u32 = 8;
+
+#[derive(Debug, thiserror::Error)]
+pub enum WorldFileError {
+    #[error("unable to access world file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("not a world file")]
+    BadMagic,
+    #[error("world file version {found} is not supported, expected version {FORMAT_VERSION}")]
+    UnsupportedVersion { found: u32 },
+    #[error("malformed world file: {0}")]
+    Encoding(#[from] serde_binary::Error),
+    #[error("corrupted world file: {0}")]
+    Corrupted(String),
+    #[error("invalid tree in world file: {0}")]
+    Invalid(#[from] ContreeError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct WorldBody {
+    size: u32,
+    max_depth: u8,
+    // glam's tuple struct encoding does not roundtrip through serde-binary
+    center_offset: [f64; 3],
+    root: Option<Addr>,
+    inners: Vec<ContreeInner>,
+    leaves: Vec<ContreeLeaf>,
+    palette: Palette,
+    journal: Journal,
+}
+
+impl<'a> Contree<'a> {
+    /// Write the tree in the world file format
+    pub fn save(&self, mut writer: impl Write) -> Result<(), WorldFileError> {
+        let CompactArenas { inners, leaves } = self.compacted();
+        let body = WorldBody {
+            size: self.size,
+            max_depth: self.max_depth,
+            center_offset: self.center_offset.to_array(),
+            root: self.root.map(|_| 0),
+            inners,
+            leaves,
+            palette: self.palette.clone(),
+            journal: self.journal.clone(),
+        };
+
+        writer.write_all(&MAGIC)?;
+        writer.write_all(&FORMAT_VERSION.to_le_bytes())?;
+        writer.write_all(&serde_binary::to_vec(&body, Endian::Little)?)?;
+        Ok(())
+    }
+
+    /// Read a tree written by [`Contree::save`], uploading all of it through the binding
+    pub fn load(
+        mut reader: impl Read,
+        binding: &'a dyn GPUBindable,
+    ) -> Result<Self, WorldFileError> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(WorldFileError::BadMagic);
+        }
+
+        let mut version = [0; 4];
+        reader.read_exact(&mut version)?;
+        let version = u32::from_le_bytes(version);
+        if version != FORMAT_VERSION {
+            return Err(WorldFileError::UnsupportedVersion { found: version });
+        }
+
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        let body: WorldBody = serde_binary::from_slice(&bytes, Endian::Little)?;
+
+        Self::check_size(body.size)?;
+        if body
+            .root
+            .is_some_and(|root| root as usize >= body.inners.len())
+        {
+            return Err(WorldFileError::Corrupted(
+                "root is not an inner node".into(),
+            ));
+        }
+        if !body.palette.is_valid() {
+            return Err(WorldFileError::Corrupted(
+                "palette does not start with air".into(),
+            ));
+        }
+
+        let contree = Self {
+            center_offset: DVec3::from_array(body.center_offset),
+            root: body.root,
+            size: body.size,
+            inners: body.inners.into(),
+            leaves: body.leaves.into(),
+            inner_tombstones: Vec::new(),
+            leaf_tombstones: Vec::new(),
+            shrink_on_remove: false,
+            max_depth: body.max_depth,
+            palette: body.palette,
+            journal: body.journal,
+            binding,
+        };
+        // edits follow links without checking them, so a broken one is rejected here
+        if let Err(violations) = contree.validate() {
+            return Err(WorldFileError::Corrupted(violations[0].to_string()));
+        }
+        contree.write_all();
+        Ok(contree)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use glam::Vec3;
+
+    use super::*;
+    use crate::test_util::create_contree;
+    use crate::{DummyBinding, Material};
+
+    #[derive(Debug, Default)]
+    struct WriteCounter {
+        inners: AtomicUsize,
+        leaves: AtomicUsize,
+    }
+    impl GPUBindable for WriteCounter {
+        fn write_inner(&self, _: Addr, data: &[ContreeInner]) {
+            self.inners.fetch_add(data.len(), Ordering::Relaxed);
+        }
+        fn write_leaf(&self, _: Addr, data: &[ContreeLeaf]) {
+            self.leaves.fetch_add(data.len(), Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn save_load_roundtrip() {
+        let mut contree = create_contree(256, Vec3::ZERO);
+        contree.center_offset = DVec3::new(3., -2., 1.);
+        contree.max_depth = 6;
+        let points: Vec<_> = (-30..30)
+            .map(|i| Vec3::new(i as f32 * 4., (i * i) as f32 / 10., -i as f32))
+            .collect();
+        for (i, &p) in points.iter().enumerate() {
+            contree.insert(p, i as u8 + 1).unwrap();
+        }
+        contree.remove(points[3]).unwrap();
+        contree
+            .add_material("stone", Material::new([0.4, 0.4, 0.4, 1.], 0.))
+            .unwrap();
+
+        let mut file = Vec::new();
+        contree.save(&mut file).unwrap();
+        let loaded = Contree::load(file.as_slice(), &DummyBinding).unwrap();
+
+        assert_eq!(loaded.size, contree.size);
+        assert_eq!(loaded.center_offset, contree.center_offset);
+        assert_eq!(loaded.max_depth, contree.max_depth);
+        assert_eq!(loaded.root, Some(0));
+        assert_eq!(loaded.palette, contree.palette);
+        assert!(loaded.leaf_tombstones.is_empty());
+        assert_eq!(
+            loaded.leaves.len(),
+            contree.leaves.len() - contree.leaf_tombstones.len()
+        );
+        for &p in &points {
+            assert_eq!(
+                loaded.find(p).unwrap().material,
+                contree.find(p).unwrap().material
+            );
+        }
+    }
+
+    #[test]
+    fn load_uploads_everything() {
+        let contree = create_contree(64, Vec3::ZERO);
+        let mut file = Vec::new();
+        contree.save(&mut file).unwrap();
+
+        let counter = WriteCounter::default();
+        let loaded = Contree::load(file.as_slice(), &counter).unwrap();
+
+        assert_eq!(counter.inners.load(Ordering::Relaxed), loaded.inners.len());
+        assert_eq!(counter.leaves.load(Ordering::Relaxed), loaded.leaves.len());
+    }
+
+    #[test]
+    fn load_rejects_other_versions() {
+        let contree = create_contree(64, Vec3::ZERO);
+        let mut file = Vec::new();
+        contree.save(&mut file).unwrap();
+        file[4..8].copy_from_slice(&0_u32.to_le_bytes());
+
+        assert!(matches!(
+            Contree::load(file.as_slice(), &DummyBinding),
+            Err(WorldFileError::UnsupportedVersion { found: 0 })
+        ));
+    }
+
+    #[test]
+    fn load_rejects_garbage() {
+        assert!(matches!(
+            Contree::load(b"not a world".as_slice(), &DummyBinding),
+            Err(WorldFileError::BadMagic)
+        ));
+        assert!(matches!(
+            Contree::load(b"VX".as_slice(), &DummyBinding),
+            Err(WorldFileError::Io(_))
+        ));
+
+        let contree = create_contree(64, Vec3::ZERO);
+        let mut file = Vec::new();
+        contree.save(&mut file).unwrap();
+        file.truncate(file.len() - 10);
+        assert!(matches!(
+            Contree::load(file.as_slice(), &DummyBinding),
+            Err(WorldFileError::Encoding(_))
+        ));
+    }
+
+    #[test]
+    fn load_rejects_broken_links() {
+        let contree = create_contree(64, Vec3::ZERO);
+        let mut file = Vec::new();
+        contree.save(&mut file).unwrap();
+
+        let mut body: WorldBody = serde_binary::from_slice(&file[8..], Endian::Little).unwrap();
+        let index = body.inners[0].contains.trailing_zeros() as usize;
+        body.inners[0].children[index] = 1000;
+        file.truncate(8);
+        file.extend(serde_binary::to_vec(&body, Endian::Little).unwrap());
+
+        let counter = WriteCounter::default();
+        assert!(matches!(
+            Contree::load(file.as_slice(), &counter),
+            Err(WorldFileError::Corrupted(_))
+        ));
+        assert_eq!(counter.inners.load(Ordering::Relaxed), 0);
+    }
+}
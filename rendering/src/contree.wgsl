@@ -7,7 +7,10 @@ struct ContreeLeaf {
 struct ContreeInner {
     contains: array<u32, 2>,
     leaf: array<u32, 2>,
+    solid: array<u32, 2>,
     light: array<u32, 2>,
+    material: u32,
+    padding: u32,
     children: array<u32, 64>,
 }
 
@@ -20,6 +23,7 @@ struct ContreeData {
 struct Material {
     color: vec4f,
     reflectivity: f32,
+    emission: f32,
 }
 
 @group(0) @binding(0) var<storage, read> inners: array<ContreeInner>;
@@ -82,79 +86,49 @@ fn raycast(p: vec3f, dir: vec3f, bounces: u32) -> vec3f {
     return vec3f(0.);
 }
 
-// convert from a signed coordinate in the range of the contree to an unsigned
-// contree-coordinate
-fn normalize_coord(p: vec3i) -> vec3u {
-    return vec3u(p - vec3i(round(contree.center_offset)) + vec3i(contree.size));
+// convert from a position in the range of the contree to an unsigned
+// contree-coordinate, matching `Contree::normalize`
+fn normalize_coord(p: vec3f) -> vec3u {
+    return vec3u(p - contree.center_offset + f32(contree.size + 1u) / 2.);
 }
 
-// interleave a 32bit number with zero to get a 64 bit number
-fn interleave(val: u32) -> array<u32, 2> {
-    var magic_numbers = array<array<u32, 2>, 5>(
-        array(0x001f0000u, 0x0000ffffu),
-        array(0x001f0000u, 0xff0000ffu),
-        array(0x100f00ffu, 0x0f00f00fu),
-        array(0x10c30c30u, 0xc30c30c3u),
-        array(0x12492492u, 0x49249249u),
-    );
-
-    // only the first 21 bits are used
-    var x = array<u32, 2>(0u, val & 0x1fffffu);
-
-    x[0] = x[1] & magic_numbers[0][0];
-    x[1] = x[1] & magic_numbers[0][1];
-
-    x[0] = (x[0] | (x[0] << 16u) | (x[1] >> 16u)) & magic_numbers[1][0];
-    x[1] = (x[1] | (x[1] << 16u)) & magic_numbers[1][1];
-
-    x[0] = (x[0] | (x[0] << 8u) | (x[1] >> 24u)) & magic_numbers[2][0];
-    x[1] = (x[1] | (x[1] << 8u)) & magic_numbers[2][1];
-
-    x[0] = (x[0] | (x[0] << 4u) | (x[1] >> 28u)) & magic_numbers[3][0];
-    x[1] = (x[1] | (x[1] << 4u)) & magic_numbers[3][1];
-
-    x[0] = (x[0] | (x[0] << 2u) | (x[1] >> 30u)) & magic_numbers[4][0];
-    x[1] = (x[1] | (x[1] << 2u)) & magic_numbers[4][1];
-
-    return x;
-}
-
-// calculate the morton code for a normalized coordinate
-fn morton_code(p: vec3u) -> array<u32, 2> {
-    let x_interleaved = interleave(p.x);
-    let x_upper = ((x_interleaved[0] << 2u) | (x_interleaved[1] >> 30u));
-    let x_lower = x_interleaved[1] << 2u;
-
-    let y_interleaved = interleave(p.y);
-    let y_upper = ((x_interleaved[0] << 1u) | (x_interleaved[1] >> 31u));
-    let y_lower = y_interleaved[1] << 1u;
-
-    let z_interleaved = interleave(p.z);
+// morton index of the voxels within a leaf, matching `util::MAX_MORTON_INDEX`
+const MAX_MORTON_INDEX: u32 = 12u;
 
-    return array<u32, 2>(
-        x_upper | y_upper | z_interleaved[0],
-        x_lower | y_lower | z_interleaved[1]
-    );
+// morton index of the children of the root
+fn first_morton_index() -> u32 {
+    return MAX_MORTON_INDEX + 1u - firstTrailingBit(contree.size) / 2u;
 }
 
-struct ChildIndexes {
-    indexes: array<u32, 10>,
-    count: u32,
+// calculate the 128 bit morton code for a normalized coordinate, most
+// significant word first, with x in the highest bit of each triple
+fn morton_code(p: vec3u) -> array<u32, 4> {
+    var code = array<u32, 4>(0u, 0u, 0u, 0u);
+    for (var bit = 0u; bit < 32u; bit++) {
+        let triple = (((p.x >> bit) & 1u) << 2u) | (((p.y >> bit) & 1u) << 1u) | ((p.z >> bit) & 1u);
+        let position = bit * 3u;
+        let word = 3u - position / 32u;
+        let offset = position % 32u;
+
+        code[word] |= triple << offset;
+        // the triple straddles two words
+        if offset > 29u {
+            code[word - 1u] |= triple >> (32u - offset);
+        }
+    }
+    return code;
 }
 
-// convert from morton codes to child indexes for traversal
-fn to_base_64(code: array<u32, 2>) -> ChildIndexes {
-    var digits = array<u32, 10>(64, 64, 64, 64, 64, 64, 64, 64, 64, 64);
+// child index at a given morton index, matching `util::morton_index`
+fn morton_index(code: array<u32, 4>, index: u32) -> u32 {
+    let shift = 6u * (MAX_MORTON_INDEX - index);
+    let word = 3u - shift / 32u;
+    let offset = shift % 32u;
 
-    var n = code;
-    var i = 0u;
-    while n[0] != 0u || n[1] != 0u {
-        digits[i] = n[1] & 0x3fu;
-
-        n[1] = (n[0] << 26u) | (n[1] >> 6u);
-        n[0] >>= 6u;
-        i++;
+    var bits = code[word] >> offset;
+    // the index straddles two words
+    if offset > 26u {
+        bits |= code[word - 1u] << (32u - offset);
     }
-
-    return ChildIndexes(digits, i);
+    return bits & 0x3fu;
 }
@@ -1,7 +1,10 @@
-use rendering::viewer::App;
+use contree::BatchingBinding;
+use rendering::{renderer::ChannelBinding, viewer::App};
 
 fn main() {
-    let mut app = App::default();
+    let (binding, buffer_reader) = ChannelBinding::channel();
+    let binding = BatchingBinding::new(binding);
+    let mut app = App::new(&binding, buffer_reader);
 
     app.view().unwrap();
 }
@@ -1,39 +1,100 @@
-use std::sync::Arc;
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use bytemuck::cast_slice;
-use flume::Sender;
-use glam::{Mat4, Vec3, Vec4Swizzles, vec4};
+use flume::{Receiver, Sender};
+use glam::{DVec3, Mat4, Vec3, Vec4Swizzles, vec4};
+use parking_lot::Mutex;
 
-use contree::{Addr, Contree, ContreeInner, ContreeLeaf, GPUBindable};
+use contree::{
+    Addr, BatchingBinding, Contree, ContreeInner, ContreeLeaf, GPUBindable, Material,
+    palette::MAX_MATERIALS,
+};
 
+use crate::viewer::TITLE;
+
+/// Sends every write down a channel, for the renderer to copy into its buffers each frame
+///
+/// Writes name the buffer they are meant for instead of holding it, so the binding does not
+/// depend on the device and can outlive any renderer using it.
 #[derive(Debug, Clone)]
 pub struct ChannelBinding {
     pub writer: Sender<BufferWriteCommand>,
-    pub inner_buffer: wgpu::Buffer,
-    pub leaf_buffer: wgpu::Buffer,
+    /// Latest bounds of the tree, pushed to the shader every frame
+    pub bounds: Arc<Mutex<ContreeData>>,
+    /// Lengths of the inner and leaf arenas, the node buffers grow to fit them before each frame
+    pub arenas: Arc<Mutex<(usize, usize)>>,
 }
 
-impl GPUBindable for ChannelBinding {
-    fn write_inner(&self, addr: Addr, data: &[ContreeInner]) {
+impl ChannelBinding {
+    /// Binding and the receiving end of its writes
+    pub fn channel() -> (Self, Receiver<BufferWriteCommand>) {
+        let (writer, reader) = flume::unbounded();
+        let binding = Self {
+            writer,
+            bounds: Default::default(),
+            arenas: Default::default(),
+        };
+        (binding, reader)
+    }
+
+    fn send(&self, target: TargetBuffer, offset: u64, data: &[u8]) {
         let _ = self.writer.send(BufferWriteCommand {
-            target_buffer: self.inner_buffer.clone(),
-            offset: addr as u64 * size_of::<ContreeInner>() as u64,
-            new_data: cast_slice(data).to_vec(),
+            target,
+            offset,
+            new_data: data.to_vec(),
         });
     }
+}
+
+impl GPUBindable for ChannelBinding {
+    fn write_inner(&self, addr: Addr, data: &[ContreeInner]) {
+        let offset = addr as u64 * size_of::<ContreeInner>() as u64;
+        self.send(TargetBuffer::InnerNodes, offset, cast_slice(data));
+    }
 
     fn write_leaf(&self, addr: Addr, data: &[ContreeLeaf]) {
-        let _ = self.writer.send(BufferWriteCommand {
-            target_buffer: self.inner_buffer.clone(),
-            offset: addr as u64 * size_of::<ContreeLeaf>() as u64,
-            new_data: cast_slice(data).to_vec(),
-        });
+        let offset = addr as u64 * size_of::<ContreeLeaf>() as u64;
+        self.send(TargetBuffer::LeafNodes, offset, cast_slice(data));
+    }
+
+    fn write_materials(&self, data: &[Material]) {
+        self.send(TargetBuffer::Materials, 0, cast_slice(data));
+    }
+
+    fn bounds_changed(&self, root: Option<Addr>, size: u32, center_offset: DVec3) {
+        *self.bounds.lock() = ContreeData {
+            size,
+            root_addr: root.unwrap_or_default(),
+            center_offset: center_offset.as_vec3().to_array(),
+            _padding: [0; 3],
+        };
     }
+
+    fn arenas_grown(&self, inners: usize, leaves: usize) {
+        *self.arenas.lock() = (inners, leaves);
+    }
+}
+
+/// Shortest time between two updates of the tree statistics in the window title
+const TITLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Nodes each node buffer has room for before it first grows
+const INITIAL_BUFFER_NODES: u64 = 1024;
+
+/// One of the buffers of [`Buffers`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TargetBuffer {
+    InnerNodes,
+    LeafNodes,
+    Materials,
 }
 
 #[derive(Debug)]
 pub struct BufferWriteCommand {
-    pub target_buffer: wgpu::Buffer,
+    pub target: TargetBuffer,
     pub offset: u64,
     pub new_data: Vec<u8>,
 }
@@ -50,7 +111,7 @@ pub struct Camera {
 }
 
 #[repr(C, align(16))]
-#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+#[derive(Debug, Default, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct ContreeData {
     size: u32,
     root_addr: u32,
@@ -82,19 +143,35 @@ impl Default for Camera {
 }
 
 #[derive(Debug)]
-pub struct Renderer {
+pub struct Renderer<'a> {
     state: State,
     pub window: Arc<winit::window::Window>,
     pub camera: Camera,
-    pub contree: Contree,
+    pub contree: Contree<'a>,
+    /// Binding of the tree, its writes are sent to the GPU once per frame
+    binding: &'a BatchingBinding<ChannelBinding>,
     pub buffers: Arc<Buffers>,
-    buffer_reader: flume::Receiver<BufferWriteCommand>,
+    buffer_reader: Receiver<BufferWriteCommand>,
+    /// The tree changed since the title was last updated
+    title_stale: bool,
+    title_updated: Option<Instant>,
 }
 
 #[derive(Debug)]
 pub struct Buffers {
     pub inner_nodes: wgpu::Buffer,
     pub leaf_nodes: wgpu::Buffer,
+    pub materials: wgpu::Buffer,
+}
+
+impl Buffers {
+    pub fn get(&self, target: TargetBuffer) -> &wgpu::Buffer {
+        match target {
+            TargetBuffer::InnerNodes => &self.inner_nodes,
+            TargetBuffer::LeafNodes => &self.leaf_nodes,
+            TargetBuffer::Materials => &self.materials,
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -106,6 +183,7 @@ pub struct State {
     queue: wgpu::Queue,
     config: wgpu::SurfaceConfiguration,
     pipeline: wgpu::RenderPipeline,
+    bind_group_layout: wgpu::BindGroupLayout,
     bind_group: wgpu::BindGroup,
     buffers: Arc<Buffers>,
 }
@@ -187,45 +265,42 @@ impl State {
                     },
                     count: None,
                 },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
             ],
         });
 
-        let inner_nodes = device.create_buffer(&wgpu::BufferDescriptor {
-            label: Some("Inner Node Arena"),
-            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
-            size: device.limits().max_storage_buffer_binding_size as u64,
-            mapped_at_creation: false,
-        });
+        let inner_nodes = create_storage_buffer(
+            &device,
+            TargetBuffer::InnerNodes,
+            INITIAL_BUFFER_NODES * size_of::<ContreeInner>() as u64,
+        );
+        let leaf_nodes = create_storage_buffer(
+            &device,
+            TargetBuffer::LeafNodes,
+            INITIAL_BUFFER_NODES * size_of::<ContreeLeaf>() as u64,
+        );
 
-        let leaf_nodes = device.create_buffer(&wgpu::BufferDescriptor {
-            label: Some("Leaf Node Arena"),
-            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
-            size: device.limits().max_storage_buffer_binding_size as u64,
-            mapped_at_creation: false,
-        });
+        let materials = create_storage_buffer(
+            &device,
+            TargetBuffer::Materials,
+            (MAX_MATERIALS * size_of::<Material>()) as u64,
+        );
 
-        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            label: Some("Render Group"),
-            layout: &bind_group_layout,
-            entries: &[
-                wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
-                        buffer: &inner_nodes,
-                        offset: 0,
-                        size: None,
-                    }),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 1,
-                    resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
-                        buffer: &leaf_nodes,
-                        offset: 0,
-                        size: None,
-                    }),
-                },
-            ],
-        });
+        let buffers = Buffers {
+            inner_nodes,
+            leaf_nodes,
+            materials,
+        };
+        let bind_group = create_bind_group(&device, &bind_group_layout, &buffers);
 
         let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
             label: Some("Pipeline Layout"),
@@ -270,30 +345,114 @@ impl State {
             queue,
             config,
             pipeline,
+            bind_group_layout,
             bind_group,
-            buffers: Arc::new(Buffers {
-                inner_nodes,
-                leaf_nodes,
-            }),
+            buffers: Arc::new(buffers),
         })
     }
+
+    /// Replace a node buffer too small for `bytes` by a larger one holding the same contents
+    fn grow_buffer(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        target: TargetBuffer,
+        bytes: u64,
+    ) -> wgpu::Buffer {
+        let buffer = self.buffers.get(target);
+        if bytes <= buffer.size() {
+            return buffer.clone();
+        }
+        let max = self.device.limits().max_storage_buffer_binding_size as u64;
+        if bytes > max {
+            tracing::warn!(
+                ?target,
+                bytes,
+                max,
+                "node arena does not fit in a GPU buffer"
+            );
+        }
+        let grown = create_storage_buffer(&self.device, target, bytes.next_power_of_two().min(max));
+        encoder.copy_buffer_to_buffer(buffer, 0, &grown, 0, buffer.size());
+        grown
+    }
 }
 
-impl Renderer {
-    pub async fn new(window: Arc<winit::window::Window>) -> anyhow::Result<Self> {
+fn create_storage_buffer(device: &wgpu::Device, target: TargetBuffer, size: u64) -> wgpu::Buffer {
+    let label = match target {
+        TargetBuffer::InnerNodes => "Inner Node Arena",
+        TargetBuffer::LeafNodes => "Leaf Node Arena",
+        TargetBuffer::Materials => "Material Palette",
+    };
+    device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some(label),
+        usage: wgpu::BufferUsages::STORAGE
+            | wgpu::BufferUsages::COPY_DST
+            | wgpu::BufferUsages::COPY_SRC,
+        size,
+        mapped_at_creation: false,
+    })
+}
+
+fn create_bind_group(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    buffers: &Buffers,
+) -> wgpu::BindGroup {
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: Some("Render Group"),
+        layout,
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                    buffer: &buffers.inner_nodes,
+                    offset: 0,
+                    size: None,
+                }),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                    buffer: &buffers.leaf_nodes,
+                    offset: 0,
+                    size: None,
+                }),
+            },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                    buffer: &buffers.materials,
+                    offset: 0,
+                    size: None,
+                }),
+            },
+        ],
+    })
+}
+
+impl<'a> Renderer<'a> {
+    /// Renderer drawing a new tree uploaded through `binding`, whose writes arrive at `buffer_reader`
+    pub async fn new(
+        window: Arc<winit::window::Window>,
+        binding: &'a BatchingBinding<ChannelBinding>,
+        buffer_reader: Receiver<BufferWriteCommand>,
+    ) -> anyhow::Result<Self> {
         let state = State::new(window.clone()).await?;
-        let (buffer_writer, buffer_reader) = flume::unbounded();
+        // writes of a previous renderer's tree mean nothing to the new buffers
+        binding.resync();
+        binding.flush();
+        buffer_reader.drain();
+        *binding.target.arenas.lock() = (0, 0);
         Ok(Self {
             window,
             buffers: state.buffers.clone(),
-            contree: Contree::new(Box::new(ChannelBinding {
-                writer: buffer_writer,
-                inner_buffer: state.buffers.inner_nodes.clone(),
-                leaf_buffer: state.buffers.leaf_nodes.clone(),
-            })),
+            contree: Contree::new(binding)?,
+            binding,
             state,
             camera: Default::default(),
             buffer_reader,
+            title_stale: true,
+            title_updated: None,
         })
     }
 
@@ -341,12 +500,7 @@ impl Renderer {
         render_pass.set_push_constants(
             wgpu::ShaderStages::FRAGMENT,
             size_of::<Camera>() as u32,
-            bytemuck::bytes_of(&ContreeData {
-                size: self.contree.size,
-                root_addr: self.contree.root,
-                center_offset: self.contree.center_offset.to_array(),
-                _padding: [0; 3],
-            }),
+            bytemuck::bytes_of(&*self.binding.target.bounds.lock()),
         );
         render_pass.set_bind_group(0, &self.state.bind_group, &[]);
         render_pass.draw(0..3, 0..1);
@@ -367,11 +521,39 @@ impl Renderer {
                     label: Some("Render Encoder"),
                 });
 
+        // the tree only changes when there are writes, and counting walks all of it,
+        // so the title is updated at most once per interval while it keeps changing
+        self.title_stale |= self.binding.is_dirty();
+        if self.title_stale {
+            if self
+                .title_updated
+                .is_none_or(|updated| updated.elapsed() >= TITLE_INTERVAL)
+            {
+                self.window
+                    .set_title(&format!("{TITLE} - {}", self.contree.stats()));
+                self.title_stale = false;
+                self.title_updated = Some(Instant::now());
+            } else {
+                // the last change still has to reach the title once edits stop
+                self.window.request_redraw();
+            }
+        }
+        self.binding.flush();
+        self.grow_buffers(&mut encoder);
         let mut belt = wgpu::util::StagingBelt::new(1024);
         for command in self.buffer_reader.try_iter() {
+            let buffer = self.buffers.get(command.target);
+            if command.offset + command.new_data.len() as u64 > buffer.size() {
+                tracing::warn!(
+                    target = ?command.target,
+                    offset = command.offset,
+                    "node arenas no longer fit in their GPU buffers"
+                );
+                continue;
+            }
             let mut view = belt.write_buffer(
                 &mut encoder,
-                &command.target_buffer,
+                buffer,
                 command.offset,
                 std::num::NonZero::new(command.new_data.len() as u64).unwrap(),
                 &self.state.device,
@@ -391,6 +573,32 @@ impl Renderer {
         Ok(())
     }
 
+    /// Reallocate the node buffers the arenas outgrew, before any writes into the new space
+    fn grow_buffers(&mut self, encoder: &mut wgpu::CommandEncoder) {
+        let (inners, leaves) = *self.binding.target.arenas.lock();
+        let inner_bytes = (inners * size_of::<ContreeInner>()) as u64;
+        let leaf_bytes = (leaves * size_of::<ContreeLeaf>()) as u64;
+        if inner_bytes <= self.buffers.inner_nodes.size()
+            && leaf_bytes <= self.buffers.leaf_nodes.size()
+        {
+            return;
+        }
+
+        let buffers = Buffers {
+            inner_nodes: self
+                .state
+                .grow_buffer(encoder, TargetBuffer::InnerNodes, inner_bytes),
+            leaf_nodes: self
+                .state
+                .grow_buffer(encoder, TargetBuffer::LeafNodes, leaf_bytes),
+            materials: self.buffers.materials.clone(),
+        };
+        self.state.bind_group =
+            create_bind_group(&self.state.device, &self.state.bind_group_layout, &buffers);
+        self.state.buffers = Arc::new(buffers);
+        self.buffers = self.state.buffers.clone();
+    }
+
     pub fn camera_left_right(&mut self, dist: f32) {
         let right_dir = Mat4::from_cols_array(&self.camera.rotation_matrix) * vec4(1., 0., 0., 0.);
 
@@ -432,3 +640,34 @@ impl Renderer {
         self.window.request_redraw();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use bytemuck::Zeroable;
+
+    use super::*;
+
+    #[test]
+    fn channel_binding_targets() {
+        let (binding, reader) = ChannelBinding::channel();
+
+        binding.write_leaf(3, &[ContreeLeaf::zeroed(); 2]);
+        let command = reader.try_recv().unwrap();
+        assert_eq!(command.target, TargetBuffer::LeafNodes);
+        assert_eq!(command.offset, 3 * size_of::<ContreeLeaf>() as u64);
+        assert_eq!(command.new_data.len(), 2 * size_of::<ContreeLeaf>());
+
+        binding.write_inner(5, &[ContreeInner::zeroed()]);
+        let command = reader.try_recv().unwrap();
+        assert_eq!(command.target, TargetBuffer::InnerNodes);
+        assert_eq!(command.offset, 5 * size_of::<ContreeInner>() as u64);
+        assert_eq!(command.new_data.len(), size_of::<ContreeInner>());
+
+        binding.write_materials(&[Material::zeroed(); 4]);
+        let command = reader.try_recv().unwrap();
+        assert_eq!(command.target, TargetBuffer::Materials);
+        assert_eq!(command.offset, 0);
+
+        assert!(reader.try_recv().is_err());
+    }
+}
@@ -9,25 +9,37 @@ use winit::{
     window::{Window, WindowId},
 };
 
-use crate::renderer::Renderer;
+use contree::BatchingBinding;
+use flume::Receiver;
 
-pub struct App {
-    pub renderer: Option<Renderer>,
+use crate::renderer::{BufferWriteCommand, ChannelBinding, Renderer};
+
+/// Title of the window, followed by the tree statistics once there is a tree
+pub const TITLE: &str = "Voxel Engine";
+
+pub struct App<'a> {
+    pub renderer: Option<Renderer<'a>>,
+    /// Shared by every renderer the app creates, which is why it is owned outside of it
+    binding: &'a BatchingBinding<ChannelBinding>,
+    buffer_reader: Receiver<BufferWriteCommand>,
     last_time: std::time::Instant,
     pressed_keys: HashSet<KeyCode>,
 }
 
-impl Default for App {
-    fn default() -> Self {
+impl<'a> App<'a> {
+    pub fn new(
+        binding: &'a BatchingBinding<ChannelBinding>,
+        buffer_reader: Receiver<BufferWriteCommand>,
+    ) -> Self {
         Self {
             renderer: None,
+            binding,
+            buffer_reader,
             last_time: std::time::Instant::now(),
             pressed_keys: HashSet::new(),
         }
     }
-}
 
-impl App {
     pub fn view(&mut self) -> Result<(), EventLoopError> {
         let event_loop = EventLoop::new().unwrap();
 
@@ -37,12 +49,13 @@ impl App {
     }
 }
 
-impl ApplicationHandler for App {
+impl ApplicationHandler for App<'_> {
     fn resumed(&mut self, event_loop: &ActiveEventLoop) {
         dbg!("window resumed, reconstructing renderer");
-        let window_attributes = Window::default_attributes().with_title("Voxel Engine");
+        let window_attributes = Window::default_attributes().with_title(TITLE);
         let window = Arc::new(event_loop.create_window(window_attributes).unwrap());
-        self.renderer = Some(pollster::block_on(Renderer::new(window)).unwrap());
+        let renderer = Renderer::new(window, self.binding, self.buffer_reader.clone());
+        self.renderer = Some(pollster::block_on(renderer).unwrap());
     }
 
     fn device_event(&mut self, _event_loop: &ActiveEventLoop, _id: DeviceId, event: DeviceEvent) {